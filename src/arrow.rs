@@ -10,17 +10,39 @@ pub enum Direction {
     Down,
     Left,
     Right,
+    // pump
+    DownLeft,
+    UpLeft,
+    Center,
+    UpRight,
+    DownRight,
+    // kb7
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
 }
 
-fn int_to_direction(i: i32) -> Direction {
-    match i {
-        0 => Direction::Left,
-        1 => Direction::Down,
-        2 => Direction::Up,
-        3 => Direction::Right,
-        _ => panic!("invalid direction"),
-    }
-}
+pub const DANCE_SINGLE_DIRECTIONS: [Direction; 4] = [Direction::Left, Direction::Down, Direction::Up, Direction::Right];
+pub const PUMP_SINGLE_DIRECTIONS: [Direction; 5] = [
+    Direction::DownLeft,
+    Direction::UpLeft,
+    Direction::Center,
+    Direction::UpRight,
+    Direction::DownRight,
+];
+pub const KB7_DIRECTIONS: [Direction; 7] = [
+    Direction::Key1,
+    Direction::Key2,
+    Direction::Key3,
+    Direction::Key4,
+    Direction::Key5,
+    Direction::Key6,
+    Direction::Key7,
+];
 
 #[derive(Debug, PartialEq, Clone, Copy, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -30,6 +52,9 @@ pub enum ArrowType {
     Freeze,
     FreezeEnd,
     Mine,
+    Roll,
+    Lift,
+    Fake,
 }
 
 impl FromStr for ArrowType {
@@ -41,6 +66,9 @@ impl FromStr for ArrowType {
             "2" => Ok(ArrowType::Freeze),
             "3" => Ok(ArrowType::FreezeEnd),
             "M" => Ok(ArrowType::Mine),
+            "4" => Ok(ArrowType::Roll),
+            "L" => Ok(ArrowType::Lift),
+            "F" => Ok(ArrowType::Fake),
             _ => Err(format!("{} is not arrow type", s)),
         }
     }
@@ -49,36 +77,58 @@ impl FromStr for ArrowType {
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub struct Arrow {
     pub direction: Direction,
+    // 譜面の左から何列目か。doubleでは2P側も通し番号
+    pub column: usize,
     #[serde(rename = "type")]
     pub arrow_type: ArrowType,
     pub end: i32,
     pub end_time: f32,
+    // どちらの足で踏むか(dance-singleのみ)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub foot: Option<Foot>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Foot {
+    Left,
+    Right,
 }
 
 impl Arrow {
-    pub fn is_freeze_end(&self, direction: Direction) -> bool {
-        self.arrow_type == ArrowType::FreezeEnd && self.direction == direction
+    pub fn is_freeze_end(&self, column: usize) -> bool {
+        self.arrow_type == ArrowType::FreezeEnd && self.column == column
     }
     pub fn is_freeze(&self) -> bool {
         self.arrow_type == ArrowType::Freeze
     }
+    // rollもfreezeと同じく"3"で終わる
+    pub fn is_hold(&self) -> bool {
+        self.arrow_type == ArrowType::Freeze || self.arrow_type == ArrowType::Roll
+    }
+    // 踏む必要があるもの(地雷とfakeは含まない)
+    pub fn is_note(&self) -> bool {
+        matches!(self.arrow_type, ArrowType::Normal | ArrowType::Freeze | ArrowType::Roll | ArrowType::Lift)
+    }
 }
 
 // "0012" -> [Arrow(Up, Normal), Arrow(Right, Freeze)]
-pub fn make_arrows(s: &str) -> Vec<Arrow> {
+pub fn make_arrows(s: &str, directions: &[Direction]) -> Vec<Arrow> {
     let mut arrows: Vec<Arrow> = Vec::new();
-    if s.len() != 4 {
-        panic!("{} is not 4 length", s);
+    if s.len() != directions.len() {
+        panic!("{} is not {} length", s, directions.len());
     }
     for (i, c) in s.chars().enumerate() {
         //let ofs = i * (NOTE_UNIT / 4);
         let arrow_type = ArrowType::from_str(&c.to_string()).unwrap();
         if arrow_type != ArrowType::None {
             arrows.push(Arrow {
-                direction: int_to_direction(i as i32),
+                direction: directions[i],
+                column: i,
                 arrow_type,
                 end: 0,
                 end_time: 0.0,
+                foot: None,
             });
         }
     }
@@ -88,19 +138,23 @@ pub fn make_arrows(s: &str) -> Vec<Arrow> {
 #[test]
 fn test_make_arrows() {
     assert_eq!(
-        make_arrows("0012"),
+        make_arrows("0012", &DANCE_SINGLE_DIRECTIONS),
         vec![
             Arrow {
                 direction: Direction::Up,
+                column: 2,
                 arrow_type: ArrowType::Normal,
                 end: 0,
                 end_time: 0.0,
+                foot: None,
             },
             Arrow {
                 direction: Direction::Right,
+                column: 3,
                 arrow_type: ArrowType::Freeze,
                 end: 0,
                 end_time: 0.0,
+                foot: None,
             },
         ]
     );
@@ -115,7 +169,8 @@ pub enum Color {
     Green,
 }
 
-fn ofs_to_color(ofs: i32) -> Color {
+// 小節内の位置ではなく拍の中での位置で決める
+pub fn ofs_to_color(ofs: i32) -> Color {
     if ofs % (NOTE_UNIT / 4) == 0 {
         Color::Red
     } else if ofs % (NOTE_UNIT / 8) == 0 {
@@ -134,6 +189,8 @@ pub struct Division {
     pub color: Color,
     pub offset: i32,
     pub time: f32,
+    // 拍子を考慮した小節番号(0始まり)
+    pub measure: i32,
 }
 
 impl Division {
@@ -145,7 +202,7 @@ impl Division {
     }
 }
 
-pub fn bar_to_divisions(bar: Vec<&str>, offset: i32) -> Vec<Division> {
+pub fn bar_to_divisions(bar: Vec<&str>, offset: i32, directions: &[Direction]) -> Vec<Division> {
     let mut divisions: Vec<Division> = Vec::new();
     if NOTE_UNIT % (bar.len() as i32) != 0 {
         panic!("{:?} is not a valid var", bar);
@@ -153,30 +210,32 @@ pub fn bar_to_divisions(bar: Vec<&str>, offset: i32) -> Vec<Division> {
     let epsilon = NOTE_UNIT / (bar.len() as i32);
     for (i, division) in bar.iter().enumerate() {
         let ofs_in_bar = i as i32 * epsilon;
-        let color = ofs_to_color(ofs_in_bar);
-        let arrows = make_arrows(division);
+        let color = ofs_to_color(offset + ofs_in_bar);
+        let arrows = make_arrows(division, directions);
         if !arrows.is_empty() {
             divisions.push(Division {
                 arrows,
                 color,
                 offset: offset + ofs_in_bar,
                 time: 0.0,
+                measure: 0,
             });
         }
     }
     divisions
 }
 
-pub fn find_freeze_end(notes: &[Division], offset: i32, direction: Direction) -> i32 {
+// 終点が無ければNone
+pub fn find_freeze_end(notes: &[Division], offset: i32, column: usize) -> Option<i32> {
     for division in notes {
         if division.offset <= offset {
             continue;
         }
         for arrow in &division.arrows {
-            if arrow.is_freeze_end(direction) {
-                return division.offset;
+            if arrow.is_freeze_end(column) {
+                return Some(division.offset);
             }
         }
     }
-    panic!("no freeze end found");
+    None
 }
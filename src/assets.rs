@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// 譜面ファイルに書かれた音声や画像のパスを、StepManiaと同じように解決する
+// 大文字小文字は区別せず、見つからない場合や書かれていない場合はディレクトリ内のファイル名から推測する
+// (例: "xxx-bn.png"はバナー、"xxx-bg.png"は背景)
+
+const AUDIO_EXTENSIONS: [&str; 5] = ["ogg", "oga", "mp3", "wav", "flac"];
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "gif", "bmp", "webp"];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AssetKind {
+    Music,
+    Banner,
+    Background,
+    Jacket,
+}
+
+impl AssetKind {
+    // 譜面ファイルのキー(.dwiの音声はFILE)
+    fn keys(&self) -> &[&str] {
+        match self {
+            AssetKind::Music => &["MUSIC", "FILE"],
+            AssetKind::Banner => &["BANNER"],
+            AssetKind::Background => &["BACKGROUND"],
+            AssetKind::Jacket => &["JACKET"],
+        }
+    }
+
+    fn extensions(&self) -> &[&str] {
+        match self {
+            AssetKind::Music => &AUDIO_EXTENSIONS,
+            _ => &IMAGE_EXTENSIONS,
+        }
+    }
+
+    // 拡張子を除いたファイル名(小文字)から推測する
+    fn guess(&self, stem: &str) -> bool {
+        match self {
+            AssetKind::Music => true,
+            AssetKind::Banner => stem.ends_with("bn") || stem.contains("banner"),
+            AssetKind::Background => stem.ends_with("bg") || stem.contains("background"),
+            AssetKind::Jacket => stem.ends_with("jk") || stem.contains("jacket"),
+        }
+    }
+
+    // 書かれていなくても無いと報告するもの
+    fn required(&self) -> bool {
+        matches!(self, AssetKind::Music | AssetKind::Banner)
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            AssetKind::Music => "music",
+            AssetKind::Banner => "banner",
+            AssetKind::Background => "background",
+            AssetKind::Jacket => "jacket",
+        }
+    }
+}
+
+// 譜面ファイルのディレクトリからの相対パス
+#[derive(Debug, Default)]
+pub struct Assets {
+    pub music: Option<String>,
+    pub banner: Option<String>,
+    pub background: Option<String>,
+    pub jacket: Option<String>,
+    // 見つからなかったもの
+    pub missing: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AssetReport {
+    pub dir_name: String,
+    pub file: String,
+    pub missing: Vec<String>,
+}
+
+// パスの各部分を大文字小文字を区別せずに探す
+fn find_case_insensitive(dir: &Path, relative: &str) -> Option<PathBuf> {
+    let mut found = PathBuf::new();
+    for component in Path::new(&relative.replace('\\', "/")).components() {
+        match component {
+            Component::Normal(name) => {
+                let name = name.to_str()?.to_lowercase();
+                let entry = fs::read_dir(dir.join(&found))
+                    .ok()?
+                    .filter_map(|e| e.ok())
+                    .find(|e| e.file_name().to_str().is_some_and(|n| n.to_lowercase() == name))?;
+                found.push(entry.file_name());
+            }
+            Component::ParentDir => found.push(".."),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    dir.join(&found).is_file().then_some(found)
+}
+
+fn guess_file(dir: &Path, kind: AssetKind) -> Option<PathBuf> {
+    let mut candidates: Vec<PathBuf> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| PathBuf::from(e.file_name()))
+        .filter(|p| {
+            let extension = p.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+            let stem = p.file_stem().and_then(|s| s.to_str()).unwrap_or("").to_lowercase();
+            kind.extensions().contains(&extension.as_str()) && kind.guess(&stem)
+        })
+        .collect();
+    candidates.sort();
+    candidates.into_iter().next()
+}
+
+pub fn resolve_assets(filepath: &str, props: &HashMap<String, String>) -> Assets {
+    let dir = Path::new(filepath).parent().unwrap_or(Path::new(""));
+    // BMSの音声はキー音なので、曲全体の音声ファイルを推測しない
+    let keysounds = [".bms", ".bme", ".bhe"].iter().any(|ext| filepath.ends_with(ext));
+    let mut assets = Assets::default();
+    for kind in [AssetKind::Music, AssetKind::Banner, AssetKind::Background, AssetKind::Jacket] {
+        let value = kind.keys().iter().find_map(|k| props.get(*k)).map(|s| s.trim()).filter(|s| !s.is_empty());
+        let resolved = value
+            .and_then(|v| find_case_insensitive(dir, v))
+            .or_else(|| if kind == AssetKind::Music && keysounds { None } else { guess_file(dir, kind) })
+            .map(|p| p.to_str().unwrap().replace('\\', "/"));
+        match (&resolved, value) {
+            (None, Some(value)) => assets.missing.push(format!("{}: {} not found", kind.name(), value)),
+            (None, None) if kind.required() && !(kind == AssetKind::Music && keysounds) => {
+                assets.missing.push(format!("{}: not specified", kind.name()))
+            }
+            _ => {}
+        }
+        match kind {
+            AssetKind::Music => assets.music = resolved,
+            AssetKind::Banner => assets.banner = resolved,
+            AssetKind::Background => assets.background = resolved,
+            AssetKind::Jacket => assets.jacket = resolved,
+        }
+    }
+    assets
+}
+
+#[test]
+fn test_resolve_assets() {
+    let dir = std::env::temp_dir().join(format!("sm_to_json_assets_{}", std::process::id()));
+    fs::create_dir_all(dir.join("Sub")).unwrap();
+    for name in ["Song.OGG", "Sub/Title-BN.png", "song-bg.jpg"] {
+        fs::write(dir.join(name), b"").unwrap();
+    }
+    let filepath = dir.join("song.sm").to_str().unwrap().to_string();
+    let props: HashMap<String, String> = [("MUSIC", "song.ogg"), ("BANNER", "sub/title-bn.png"), ("JACKET", "jacket.png")]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let assets = resolve_assets(&filepath, &props);
+    assert_eq!(assets.music.as_deref(), Some("Song.OGG"));
+    assert_eq!(assets.banner.as_deref(), Some("Sub/Title-BN.png"));
+    // 書かれていない背景はファイル名から推測する
+    assert_eq!(assets.background.as_deref(), Some("song-bg.jpg"));
+    assert_eq!(assets.jacket, None);
+    assert_eq!(assets.missing, vec!["jacket: jacket.png not found"]);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,276 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+// 音声ファイルのヘッダから再生時間(秒)、サンプリング周波数、チャンネル数を読む。デコードはしない
+// .wav: fmtチャンクとdataチャンクの長さ
+// .ogg: Vorbis/Opusの識別ヘッダと最後のページのgranule position
+// .mp3: Xing/Info/VBRIヘッダのフレーム数、無ければ最初のフレームのビットレートで固定ビットレートとみなす
+// .flac: STREAMINFOのサンプル数
+// ファイル全体は読まず、ヘッダなど必要な位置だけを読む
+
+// mp3の最初のフレームを探す範囲
+const MP3_SCAN_BYTES: u64 = 64 * 1024;
+// oggのページの最大の長さ(最後のページはファイルの末尾からこの範囲にある)
+const OGG_MAX_PAGE: u64 = 27 + 255 + 255 * 255;
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct AudioInfo {
+    pub duration: f32,
+    pub sample_rate: u32,
+    pub channels: u32,
+}
+
+fn u16_le(data: &[u8], i: usize) -> Option<u32> {
+    Some(u16::from_le_bytes(data.get(i..i + 2)?.try_into().ok()?) as u32)
+}
+
+fn u32_le(data: &[u8], i: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(i..i + 4)?.try_into().ok()?))
+}
+
+fn u32_be(data: &[u8], i: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?))
+}
+
+// offsetからsizeバイトまで読む(ファイルの終わりまでしか読まない)
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, size: u64) -> Option<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset)).ok()?;
+    let mut buf = Vec::new();
+    reader.by_ref().take(size).read_to_end(&mut buf).ok()?;
+    Some(buf)
+}
+
+fn stream_len<R: Seek>(reader: &mut R) -> Option<u64> {
+    reader.seek(SeekFrom::End(0)).ok()
+}
+
+fn wav_info<R: Read + Seek>(reader: &mut R) -> Option<AudioInfo> {
+    let len = stream_len(reader)?;
+    let header = read_at(reader, 0, 12)?;
+    if header.get(0..4)? != b"RIFF" || header.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    // (チャンネル数, サンプリング周波数, バイトレート)
+    let mut format = None;
+    let mut i = 12;
+    while i + 8 <= len {
+        let chunk = read_at(reader, i, 8)?;
+        let size = u32_le(&chunk, 4)? as u64;
+        match chunk.get(0..4)? {
+            b"fmt " => {
+                let fmt = read_at(reader, i + 8, 16)?;
+                format = Some((u16_le(&fmt, 2)?, u32_le(&fmt, 4)?, u32_le(&fmt, 8)?));
+            }
+            b"data" => {
+                let (channels, sample_rate, byte_rate) = format?;
+                // dataチャンクの長さが実際より長く書かれていることがあるので、ファイルの長さで抑える
+                let duration = size.min(len - i - 8) as f32 / byte_rate as f32;
+                return Some(AudioInfo { duration, sample_rate, channels });
+            }
+            _ => {}
+        }
+        // チャンクは2バイト境界に揃えられる
+        i += 8 + size + size % 2;
+    }
+    None
+}
+
+// PCMのwavからstart秒からlength秒を切り出したwavを作る
+pub fn wav_clip(data: &[u8], start: f32, length: f32) -> Option<Vec<u8>> {
+    if data.get(0..4)? != b"RIFF" || data.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let mut fmt: Option<&[u8]> = None;
+    let mut i = 12;
+    while i + 8 <= data.len() {
+        let size = u32_le(data, i + 4)? as usize;
+        match &data[i..i + 4] {
+            b"fmt " => fmt = data.get(i..i + 8 + size),
+            b"data" => {
+                let fmt = fmt?;
+                let (byte_rate, block_align) = (u32_le(fmt, 16)? as usize, u16_le(fmt, 20)? as usize);
+                let samples = &data[i + 8..(i + 8 + size).min(data.len())];
+                // サンプルの途中で切らないようにblock_alignに揃える
+                let align = |seconds: f32| ((seconds.max(0.0) * byte_rate as f32) as usize / block_align * block_align).min(samples.len());
+                let (from, to) = (align(start), align(start + length));
+                let clip = &samples[from..to.max(from)];
+                let mut out = b"RIFF".to_vec();
+                out.extend(((4 + fmt.len() + fmt.len() % 2 + 8 + clip.len()) as u32).to_le_bytes());
+                out.extend(b"WAVE");
+                out.extend(fmt);
+                if fmt.len() % 2 == 1 {
+                    out.push(0);
+                }
+                out.extend(b"data");
+                out.extend((clip.len() as u32).to_le_bytes());
+                out.extend(clip);
+                return Some(out);
+            }
+            _ => {}
+        }
+        i += 8 + size + size % 2;
+    }
+    None
+}
+
+fn ogg_info<R: Read + Seek>(reader: &mut R) -> Option<AudioInfo> {
+    let len = stream_len(reader)?;
+    // 最初のページは識別ヘッダだけなので先頭の1ページ分で足りる
+    let data = &read_at(reader, 0, OGG_MAX_PAGE)?;
+    if data.get(0..4)? != b"OggS" {
+        return None;
+    }
+    // 最初のページの本体が識別ヘッダ
+    let segments = *data.get(26)? as usize;
+    let body = 27 + segments;
+    let (channels, sample_rate, pre_skip) = if data.get(body..body + 7)? == b"\x01vorbis" {
+        (*data.get(body + 11)? as u32, u32_le(data, body + 12)?, 0)
+    } else if data.get(body..body + 8)? == b"OpusHead" {
+        // Opusは常に48kHzで再生する
+        (*data.get(body + 9)? as u32, 48000, u16_le(data, body + 10)?)
+    } else {
+        return None;
+    };
+    let tail = read_at(reader, len.saturating_sub(OGG_MAX_PAGE), OGG_MAX_PAGE)?;
+    let last_page = tail.windows(4).rposition(|w| w == b"OggS")?;
+    let granule = u64::from_le_bytes(tail.get(last_page + 6..last_page + 14)?.try_into().ok()?);
+    let duration = granule.saturating_sub(pre_skip as u64) as f32 / sample_rate as f32;
+    Some(AudioInfo { duration, sample_rate, channels })
+}
+
+// MPEG Audio Layer IIIのフレームヘッダ
+struct Mp3Frame {
+    bitrate: u32,
+    sample_rate: u32,
+    samples_per_frame: u32,
+    channels: u32,
+    // サイド情報の長さ(Xingヘッダの位置を求めるのに使う)
+    side_info: usize,
+}
+
+fn parse_mp3_frame(header: u32) -> Option<Mp3Frame> {
+    const BITRATES_V1: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+    const BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+    if header >> 21 != 0x7ff || (header >> 17) & 3 != 1 {
+        return None;
+    }
+    // 3: MPEG1, 2: MPEG2, 0: MPEG2.5
+    let version = (header >> 19) & 3;
+    let bitrate_index = ((header >> 12) & 15) as usize;
+    let sample_rate_index = ((header >> 10) & 3) as usize;
+    if version == 1 || bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 {
+        return None;
+    }
+    let mono = (header >> 6) & 3 == 3;
+    let (bitrate, sample_rate, samples_per_frame, side_info) = match version {
+        3 => (BITRATES_V1[bitrate_index], SAMPLE_RATES[sample_rate_index], 1152, if mono { 17 } else { 32 }),
+        2 => (BITRATES_V2[bitrate_index], SAMPLE_RATES[sample_rate_index] / 2, 576, if mono { 9 } else { 17 }),
+        _ => (BITRATES_V2[bitrate_index], SAMPLE_RATES[sample_rate_index] / 4, 576, if mono { 9 } else { 17 }),
+    };
+    Some(Mp3Frame { bitrate, sample_rate, samples_per_frame, channels: if mono { 1 } else { 2 }, side_info })
+}
+
+fn mp3_info<R: Read + Seek>(reader: &mut R) -> Option<AudioInfo> {
+    let len = stream_len(reader)?;
+    // ID3v2タグを飛ばす(サイズは7bitずつのsyncsafe整数)。ジャケット画像などで大きいことがあるので読まない
+    let id3 = read_at(reader, 0, 10)?;
+    let mut tag_end = 0;
+    if id3.get(0..3) == Some(b"ID3") {
+        let size = id3.get(6..10)?.iter().fold(0u64, |acc, b| (acc << 7) | (*b & 0x7f) as u64);
+        let footer = if id3[5] & 0x10 != 0 { 10 } else { 0 };
+        tag_end = 10 + size + footer;
+    }
+    let data = &read_at(reader, tag_end, MP3_SCAN_BYTES)?;
+    let (start, frame) = (0..data.len().saturating_sub(4)).find_map(|i| parse_mp3_frame(u32_be(data, i)?).map(|f| (i, f)))?;
+    let xing = start + 4 + frame.side_info;
+    let frames = match data.get(xing..xing + 4) {
+        Some(b"Xing") | Some(b"Info") if u32_be(data, xing + 4)? & 1 != 0 => Some(u32_be(data, xing + 8)?),
+        _ if data.get(start + 36..start + 40) == Some(b"VBRI") => Some(u32_be(data, start + 50)?),
+        _ => None,
+    };
+    let duration = match frames {
+        Some(frames) => (frames * frame.samples_per_frame) as f32 / frame.sample_rate as f32,
+        None => {
+            // 末尾のID3v1タグは除く
+            let has_tag = len >= 128 && read_at(reader, len - 128, 3)? == b"TAG";
+            let end = if has_tag { len - 128 } else { len };
+            end.saturating_sub(tag_end + start as u64) as f32 * 8.0 / (frame.bitrate * 1000) as f32
+        }
+    };
+    Some(AudioInfo { duration, sample_rate: frame.sample_rate, channels: frame.channels })
+}
+
+fn flac_info<R: Read + Seek>(reader: &mut R) -> Option<AudioInfo> {
+    let data = &read_at(reader, 0, 26)?;
+    // 最初のメタデータブロックは必ずSTREAMINFO
+    if data.get(0..4)? != b"fLaC" || data.get(4)? & 0x7f != 0 {
+        return None;
+    }
+    // 18バイト目から サンプリング周波数20bit, チャンネル数-1 3bit, ビット深度-1 5bit, 総サンプル数36bit
+    let bits = u64::from_be_bytes(data.get(18..26)?.try_into().ok()?);
+    let sample_rate = (bits >> 44) as u32;
+    let channels = ((bits >> 41) & 7) as u32 + 1;
+    let samples = bits & 0xf_ffff_ffff;
+    if sample_rate == 0 {
+        return None;
+    }
+    Some(AudioInfo { duration: samples as f32 / sample_rate as f32, sample_rate, channels })
+}
+
+pub fn read_audio_info(path: &Path) -> Result<AudioInfo, String> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    let mut file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let info = match extension.as_str() {
+        "wav" => wav_info(&mut file),
+        "ogg" | "oga" | "opus" => ogg_info(&mut file),
+        "mp3" => mp3_info(&mut file),
+        "flac" => flac_info(&mut file),
+        _ => return Err(format!("{}: unsupported audio format", path.display())),
+    };
+    info.ok_or(format!("{}: cannot read audio header", path.display()))
+}
+
+#[test]
+fn test_audio_info() {
+    use std::io::Cursor;
+    // 44.1kHz 16bit ステレオで2秒のwav
+    let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x02\0".to_vec();
+    wav.extend(44100u32.to_le_bytes());
+    wav.extend((44100u32 * 4).to_le_bytes());
+    wav.extend([4, 0, 16, 0]);
+    wav.extend(b"data");
+    wav.extend((44100u32 * 8).to_le_bytes());
+    wav.extend(vec![0; 44100 * 8]);
+    assert_eq!(wav_info(&mut Cursor::new(&wav)), Some(AudioInfo { duration: 2.0, sample_rate: 44100, channels: 2 }));
+    let clip = wav_clip(&wav, 0.5, 1.0).unwrap();
+    assert_eq!(wav_info(&mut Cursor::new(&clip)), Some(AudioInfo { duration: 1.0, sample_rate: 44100, channels: 2 }));
+
+    // 128kbps 44.1kHz ステレオのフレーム(417バイト)が100個でXingヘッダ無し
+    let mut mp3 = Vec::new();
+    for _ in 0..100 {
+        mp3.extend([0xff, 0xfb, 0x90, 0x00]);
+        mp3.extend(vec![0; 413]);
+    }
+    let expected = 100.0 * 417.0 * 8.0 / 128000.0;
+    let info = mp3_info(&mut Cursor::new(&mp3)).unwrap();
+    assert!((info.duration - expected).abs() < 0.001);
+    assert_eq!((info.sample_rate, info.channels), (44100, 2));
+    // 大きなID3v2タグと末尾のID3v1タグは長さに含めない
+    let mut tagged = b"ID3\x03\0\0\0\x08\0\0".to_vec();
+    tagged.extend(vec![0; 0x20000]);
+    tagged.extend(&mp3);
+    tagged.extend(b"TAG");
+    tagged.extend(vec![0; 125]);
+    let info = mp3_info(&mut Cursor::new(&tagged)).unwrap();
+    assert!((info.duration - expected).abs() < 0.001);
+
+    // 48kHz モノラルで96000サンプルのflac
+    let mut flac = b"fLaC\x80\0\0\x22".to_vec();
+    flac.extend([0; 10]);
+    flac.extend(((48000u64 << 44) | 96000).to_be_bytes());
+    flac.extend([0; 16]);
+    assert_eq!(flac_info(&mut Cursor::new(&flac)), Some(AudioInfo { duration: 2.0, sample_rate: 48000, channels: 1 }));
+}
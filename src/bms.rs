@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use crate::arrow::NOTE_UNIT;
+use crate::chart::{Chart, ChartType, Difficulty, make_chart, rows_to_bars, str_to_notes};
+use crate::gimmick::{Bpm, Stop, TimeSignature};
+
+// BMS形式(.bms, .bme, .bhe)
+// "#mmmcc:データ" の行で、mmmが小節番号、ccがチャンネル。データは2文字ずつの36進数
+// 小節の長さはチャンネル02で変わるので、offsetは小節の先頭からの拍数で計算する
+// STOPの長さは1/192小節(=1/48拍)単位
+// #RANDOMは常に1が出たものとして扱う
+// kb7-singleとして読む。スクラッチ(チャンネルx6)の列は無いので読み飛ばす
+
+const UNITS_PER_BEAT: f32 = (NOTE_UNIT / 4) as f32;
+
+const SCRATCH: char = '6';
+
+// 1P側のチャンネルの2文字目 -> 列。5鍵は1〜5だけを使う
+fn key_to_column(key: char) -> Option<usize> {
+    "1234589".find(key)
+}
+
+fn parse_difficulty(s: &str) -> Difficulty {
+    match s.trim() {
+        "1" => Difficulty::Beginner,
+        "2" => Difficulty::Easy,
+        "3" => Difficulty::Medium,
+        "4" => Difficulty::Hard,
+        "5" => Difficulty::Challenge,
+        _ => Difficulty::Edit,
+    }
+}
+
+struct BmsFile {
+    headers: HashMap<String, String>,
+    // (小節, チャンネル, データ)
+    channels: Vec<(i32, String, String)>,
+}
+
+fn parse_bms(contents: &str) -> BmsFile {
+    let mut headers = HashMap::new();
+    let mut channels = Vec::new();
+    // #IFの入れ子ごとに、その中を読むかどうか
+    let mut active: Vec<bool> = Vec::new();
+    for line in contents.lines().map(|l| l.trim()).filter(|l| l.starts_with('#')) {
+        let line = &line[1..];
+        let upper = line.to_uppercase();
+        if upper.starts_with("IF") {
+            active.push(line[2..].trim() == "1");
+            continue;
+        } else if upper.starts_with("ENDIF") {
+            active.pop();
+            continue;
+        } else if active.iter().any(|a| !a) {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((head, data)) if head.len() == 5 && head[..3].chars().all(|c| c.is_ascii_digit()) => {
+                channels.push((head[..3].parse().unwrap(), head[3..].to_uppercase(), data.trim().to_string()));
+            }
+            _ => {
+                let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+                headers.insert(key.to_uppercase(), value.trim().to_string());
+            }
+        }
+    }
+    BmsFile { headers, channels }
+}
+
+fn objects(data: &str) -> Vec<String> {
+    data.as_bytes().chunks(2).map(|c| String::from_utf8_lossy(c).to_uppercase()).collect()
+}
+
+// 各小節の長さ(4/4を1とする)
+fn measure_lengths(bms: &BmsFile) -> Vec<f32> {
+    let last = bms.channels.iter().map(|c| c.0).max().unwrap_or(0);
+    let mut lengths = vec![1.0; (last + 2) as usize];
+    for (measure, channel, data) in &bms.channels {
+        if channel == "02" {
+            lengths[*measure as usize] = data.parse().unwrap();
+        }
+    }
+    lengths
+}
+
+// 各小節の先頭の位置(拍)
+fn measure_starts(bms: &BmsFile) -> Vec<f32> {
+    let mut starts = vec![0.0];
+    for length in measure_lengths(bms) {
+        let last = *starts.last().unwrap();
+        starts.push(last + length * 4.0);
+    }
+    starts
+}
+
+// 小節の長さを n/64 までの拍子に直す
+fn length_to_time_signature(offset: i32, length: f32) -> TimeSignature {
+    let denominator = [4, 8, 16, 32, 64]
+        .into_iter()
+        .find(|d| (length * *d as f32 - (length * *d as f32).round()).abs() < 0.001)
+        .unwrap_or(64);
+    TimeSignature {
+        offset,
+        numerator: ((length * denominator as f32).round() as i32).max(1),
+        denominator,
+    }
+}
+
+// 小節の長さが変わるところに拍子を置く
+fn time_signatures(bms: &BmsFile, starts: &[f32]) -> Vec<TimeSignature> {
+    let mut signatures: Vec<TimeSignature> = Vec::new();
+    let mut prev = 1.0;
+    for (measure, length) in measure_lengths(bms).into_iter().enumerate() {
+        if (length - prev).abs() > 0.0001 || (measure == 0 && length != 1.0) {
+            signatures.push(length_to_time_signature((starts[measure] * UNITS_PER_BEAT).round() as i32, length));
+        }
+        prev = length;
+    }
+    signatures
+}
+
+// 小節内のi/n番目の位置をoffsetにする
+fn position(starts: &[f32], measure: i32, i: usize, n: usize) -> i32 {
+    let (start, end) = (starts[measure as usize], starts[measure as usize + 1]);
+    ((start + (end - start) * i as f32 / n as f32) * UNITS_PER_BEAT).round() as i32
+}
+
+pub fn bms_to_chart(filepath: &String) -> Vec<Chart> {
+    let contents = String::from_utf8_lossy(&fs::read(filepath).expect("file open error")).to_string();
+    let bms = parse_bms(&contents);
+    let starts = measure_starts(&bms);
+    let chart_type = ChartType::Kb7Single;
+    let width = chart_type.directions().len();
+    let scratches = bms
+        .channels
+        .iter()
+        .filter(|(_, channel, _)| channel.ends_with(SCRATCH) && (channel.starts_with('1') || channel.starts_with('5')))
+        .map(|(_, _, data)| objects(data).iter().filter(|o| *o != "00").count())
+        .sum::<usize>();
+    if scratches > 0 {
+        eprintln!("WARNING: {}: {} scratch objects are skipped (kb7-single has no scratch lane)", filepath, scratches);
+    }
+
+    let mut bpms = vec![Bpm {
+        offset: 0,
+        bpm: bms.headers.get("BPM").map_or(130.0, |s| s.parse().unwrap()),
+    }];
+    let mut stop_beats: Vec<(i32, f32)> = Vec::new();
+    let mut rows: BTreeMap<i32, String> = BTreeMap::new();
+    let lnobj = bms.headers.get("LNOBJ").map(|s| s.to_uppercase());
+    // 列ごとの直前のノーツ(LNOBJ用)とLNの始点(チャンネル5x用)
+    let mut last_note: Vec<Option<i32>> = vec![None; width];
+    let mut ln_start: Vec<Option<i32>> = vec![None; width];
+
+    let mut events: Vec<(i32, &str, String)> = Vec::new();
+    for (measure, channel, data) in &bms.channels {
+        let objs = objects(data);
+        for (i, obj) in objs.iter().enumerate().filter(|(_, o)| o.as_str() != "00") {
+            events.push((position(&starts, *measure, i, objs.len()), channel.as_str(), obj.clone()));
+        }
+    }
+    events.sort_by_key(|e| e.0);
+
+    let set = |rows: &mut BTreeMap<i32, String>, offset: i32, column: usize, c: char| {
+        let row = rows.entry(offset).or_insert_with(|| "0".repeat(width));
+        row.replace_range(column..column + 1, &c.to_string());
+    };
+    for (offset, channel, obj) in events {
+        let mut chars = channel.chars();
+        let (kind, key) = (chars.next().unwrap(), chars.next().unwrap());
+        match (kind, key) {
+            ('0', '3') => bpms.push(Bpm { offset, bpm: i32::from_str_radix(&obj, 16).unwrap() as f32 }),
+            ('0', '8') => {
+                let value = bms.headers.get(&format!("BPM{}", obj)).or_else(|| bms.headers.get(&format!("EXBPM{}", obj)));
+                bpms.push(Bpm { offset, bpm: value.unwrap().parse().unwrap() });
+            }
+            ('0', '9') => {
+                let value: f32 = bms.headers.get(&format!("STOP{}", obj)).unwrap().parse().unwrap();
+                stop_beats.push((offset, value / UNITS_PER_BEAT));
+            }
+            ('1', _) => {
+                let Some(column) = key_to_column(key) else { continue };
+                if lnobj.as_ref() == Some(&obj) {
+                    if let Some(start) = last_note[column].take() {
+                        set(&mut rows, start, column, '2');
+                        set(&mut rows, offset, column, '3');
+                    }
+                } else {
+                    set(&mut rows, offset, column, '1');
+                    last_note[column] = Some(offset);
+                }
+            }
+            ('5', _) => {
+                let Some(column) = key_to_column(key) else { continue };
+                match ln_start[column].take() {
+                    Some(start) => {
+                        set(&mut rows, start, column, '2');
+                        set(&mut rows, offset, column, '3');
+                    }
+                    None => ln_start[column] = Some(offset),
+                }
+            }
+            ('D', _) => {
+                let Some(column) = key_to_column(key) else { continue };
+                set(&mut rows, offset, column, 'M');
+            }
+            _ => {}
+        }
+    }
+    bpms.sort_by_key(|b| b.offset);
+    // STOPの長さは停止した位置のBPMで秒に変換する
+    let stops: Vec<Stop> = stop_beats
+        .into_iter()
+        .map(|(offset, beats)| {
+            let bpm = bpms.iter().rev().find(|b| b.offset <= offset).unwrap().bpm;
+            Stop { offset, time: beats * 60.0 / bpm }
+        })
+        .collect();
+
+    let difficulty = parse_difficulty(bms.headers.get("DIFFICULTY").map_or("", |s| s.as_str()));
+    let level = bms.headers.get("PLAYLEVEL").map_or(0, |s| s.parse().unwrap_or(0));
+    let bars = rows_to_bars(&rows, width);
+    let time_signatures = time_signatures(&bms, &starts);
+    let notes = str_to_notes(bars.iter().map(|s| s.as_str()).collect(), chart_type, &bpms, &stops, &time_signatures);
+    vec![make_chart(chart_type, difficulty, level, notes, &bpms, &stops, &time_signatures)]
+}
+
+// 曲情報を.smと同じキー(TITLE, MUSIC, BANNER, OFFSET)で返す
+// BMSはキー音で鳴らすので曲全体の音声ファイルは無い
+pub fn bms_props(filepath: &str) -> HashMap<String, String> {
+    let contents = String::from_utf8_lossy(&fs::read(filepath).expect("file open error")).to_string();
+    let bms = parse_bms(&contents);
+    let mut props = HashMap::new();
+    let title = match bms.headers.get("SUBTITLE") {
+        Some(subtitle) => format!("{} {}", bms.headers.get("TITLE").map_or("", |s| s.as_str()), subtitle),
+        None => bms.headers.get("TITLE").cloned().unwrap_or_default(),
+    };
+    props.insert("TITLE".to_string(), title);
+    props.insert("MUSIC".to_string(), "".to_string());
+    if let Some(banner) = bms.headers.get("BANNER").or_else(|| bms.headers.get("STAGEFILE")) {
+        props.insert("BANNER".to_string(), banner.clone());
+    }
+    props
+}
+
+#[test]
+fn test_measure_length() {
+    let bms = parse_bms("#BPM 120\n#00102:0.75\n#00111:01010101\n#00211:01\n#IF 2\n#00311:01\n#ENDIF\n");
+    let starts = measure_starts(&bms);
+    assert_eq!(&starts[..4], &[0.0, 4.0, 7.0, 11.0]);
+    // 3/4拍子の小節を4分割すると1つ3/4拍
+    assert_eq!(position(&starts, 1, 1, 4), 192 + 36);
+    assert_eq!(position(&starts, 2, 0, 1), 336);
+    assert_eq!(bms.channels.len(), 3);
+    let signatures = time_signatures(&bms, &starts);
+    assert_eq!(signatures[0], TimeSignature { offset: 192, numerator: 3, denominator: 4 });
+    assert_eq!(signatures[1], TimeSignature { offset: 336, numerator: 4, denominator: 4 });
+}
+
+#[test]
+fn test_key_to_column() {
+    assert_eq!(key_to_column(SCRATCH), None);
+    let columns: Vec<Option<usize>> = "1234589".chars().map(key_to_column).collect();
+    assert_eq!(columns, (0..7).map(Some).collect::<Vec<_>>());
+}
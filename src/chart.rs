@@ -1,18 +1,46 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::str::FromStr;
 
 
-use crate::arrow::{Arrow, ArrowType, Division, NOTE_UNIT, bar_to_divisions, find_freeze_end};
-use crate::gimmick::{Gimmick, Bpm, Stop, BpmDisplay, StopDisplay};
-use crate::groove_radar::get_groove_radar;
+use crate::arrow::{Arrow, ArrowType, Direction, Division, NOTE_UNIT, DANCE_SINGLE_DIRECTIONS, PUMP_SINGLE_DIRECTIONS, KB7_DIRECTIONS, bar_to_divisions, find_freeze_end};
+use crate::gimmick::{Gimmick, Bpm, Stop, BpmDisplay, StopDisplay, TimeSignature, TimeSignatureDisplay, measure_starts, measure_of};
+use crate::groove_radar::{GrooveRadarDetail, MusicLength, RadarEra, get_groove_radar, get_groove_radar_detail, get_song_end};
+use crate::stream::{StreamInfo, get_stream_info};
+use crate::density::{Density, DensityConfig, get_density};
+use crate::step_count::{ComboRule, StepCounts, count_steps, max_combo};
+use crate::chart_hash::{groovestats_hash, model_hash};
+use crate::parity::{ParityCounts, solve_parity};
+use crate::pattern::{PatternCounts, PatternOccurrence, find_patterns};
+use crate::scroll_speed::{DEFAULT_READ_SPEED, ScrollSpeed, get_scroll_speed};
+use crate::dwi::dwi_to_chart;
+use crate::ksf::ksf_to_chart;
+use crate::osu::osu_to_chart;
+use crate::bms::bms_to_chart;
+use crate::simfile::Format;
 
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum ChartType {
     DanceSingle,
     DanceDouble,
+    PumpSingle,
+    PumpDouble,
+    Kb7Single,
+}
+
+impl ChartType {
+    // 列ごとの向き
+    pub fn directions(&self) -> Vec<Direction> {
+        match self {
+            ChartType::DanceSingle => DANCE_SINGLE_DIRECTIONS.to_vec(),
+            ChartType::DanceDouble => DANCE_SINGLE_DIRECTIONS.repeat(2),
+            ChartType::PumpSingle => PUMP_SINGLE_DIRECTIONS.to_vec(),
+            ChartType::PumpDouble => PUMP_SINGLE_DIRECTIONS.repeat(2),
+            ChartType::Kb7Single => KB7_DIRECTIONS.to_vec(),
+        }
+    }
 }
 
 impl FromStr for ChartType {
@@ -21,12 +49,15 @@ impl FromStr for ChartType {
         match s {
             "dance-single" => Ok(ChartType::DanceSingle),
             "dance-double" => Ok(ChartType::DanceDouble),
+            "pump-single" => Ok(ChartType::PumpSingle),
+            "pump-double" => Ok(ChartType::PumpDouble),
+            "kb7-single" => Ok(ChartType::Kb7Single),
             _ => Err(format!("{} is not supported", s)),
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum Difficulty {
     Beginner,
     Easy,
@@ -51,7 +82,7 @@ impl FromStr for Difficulty {
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ChartInfo {
     pub chart_type: ChartType,
     pub difficulty: Difficulty,
@@ -63,33 +94,125 @@ pub struct ChartInfo {
     pub air: i32,
     pub freeze: i32,
     pub chaos: i32,
+    pub stream_measures: i32,
+    pub stream_density: f32,
+    pub steps: StepCounts,
+    // ファイル形式によらない譜面のハッシュ
+    pub hash: String,
+    // GrooveStatsなどのスコアのデータベースで使われるハッシュ(.sm/.sscのみ)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groovestats_hash: Option<String>,
+    // 足運びの種類ごとの回数(dance-singleのみ)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parity: Option<ParityCounts>,
+    pub patterns: PatternCounts,
+    // ハイスピードのおすすめ
+    pub scroll_speed: ScrollSpeed,
+    // 音楽の速さを変えた譜面(--rates)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rates: Vec<RatedChartInfo>,
+    // 変換した譜面なら適用したmodifier(--transform、適用した順)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<String>,
     //notes: Vec<Division>,
 }
 
+// 速さを変えた譜面のグルーブレーダーとおすすめのxMod
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RatedChartInfo {
+    pub rate: f32,
+    pub stream: i32,
+    pub voltage: i32,
+    pub air: i32,
+    pub freeze: i32,
+    pub chaos: i32,
+    pub recommended_xmod: f32,
+}
+
+impl RatedChartInfo {
+    pub fn from_info(rate: f32, info: &ChartInfo) -> RatedChartInfo {
+        RatedChartInfo {
+            rate,
+            stream: info.stream,
+            voltage: info.voltage,
+            air: info.air,
+            freeze: info.freeze,
+            chaos: info.chaos,
+            recommended_xmod: info.scroll_speed.recommended_xmod,
+        }
+    }
+}
+
 // TODO: contentの公開をやめて、dump的なmethodを公開すべき
 pub struct Chart {
     pub info: ChartInfo,
     //notes: Vec<Division>,
     pub content: LegacyChartContent,
+    pub bpms: Vec<Bpm>,
+    pub stops: Vec<Stop>,
+    pub time_signatures: Vec<TimeSignature>,
+    // グルーブレーダーで使う曲の長さ
+    pub music_length: MusicLength,
+}
+
+impl Chart {
+    pub fn notes(&self) -> &[Division] {
+        &self.content.stream
+    }
+    // NPSの区間の設定を変えて計算し直す
+    pub fn update_density(&mut self, config: &DensityConfig) {
+        self.content.density = get_density(&self.content.stream, &self.bpms, &self.stops, &self.time_signatures, config);
+    }
+    // 最後のノーツ(freezeの終点)とその後の停止が終わる時間(0拍目からの秒)
+    pub fn end_time(&self) -> f32 {
+        get_song_end(&self.content.stream, &self.bpms, &self.stops, &MusicLength { end: None, tail: 0.0 })
+    }
+    // 最大コンボを別のルールで数え直す
+    pub fn update_max_combo(&mut self, rule: ComboRule) {
+        self.info.max_combo = max_combo(&self.content.stream, rule);
+    }
+    // グルーブレーダーを別の時代の計算式で計算し直す
+    // with_detailなら切り捨て前の値と途中の値も譜面のjsonに出力する
+    pub fn update_groove_radar(&mut self, era: RadarEra, with_detail: bool) {
+        let detail = get_groove_radar_detail(&self.content.stream, &self.bpms, &self.stops, &self.music_length, era);
+        let radar = detail.truncate();
+        self.content.groove_radar = if with_detail { Some(detail) } else { None };
+        self.info.stream = radar.stream;
+        self.info.voltage = radar.voltage;
+        self.info.air = radar.air;
+        self.info.freeze = radar.freeze;
+        self.info.chaos = radar.chaos;
+    }
+    // 目標の速さ(BPM×倍率)を変えてハイスピードを選び直す
+    pub fn update_scroll_speed(&mut self, target_read_speed: f32) {
+        self.info.scroll_speed = get_scroll_speed(&self.content.stream, &self.bpms, target_read_speed);
+    }
 }
 
 // TODO: viewerと同時に変更する
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LegacyChartContent {
     stream: Vec<Division>,
-    stream_info: Vec<i32>,
+    stream_info: StreamInfo,
+    density: Density,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groove_radar: Option<GrooveRadarDetail>,
+    patterns: Vec<PatternOccurrence>,
     pub gimmick: Gimmick,
 }
 
-fn str_to_notes(bars: Vec<&str>, bpms: &[Bpm], stops: &[Stop]) -> Vec<Division> {
+// .sm/.sscの小節(カンマ区切り)は拍子に関係なく常に4拍
+pub fn str_to_notes(bars: Vec<&str>, chart_type: ChartType, bpms: &[Bpm], stops: &[Stop], time_signatures: &[TimeSignature]) -> Vec<Division> {
+    let directions = chart_type.directions();
     let mut notes: Vec<Division> = Vec::new();
     let mut offset = 0;
     for bar in bars {
         let divisions =
-            bar_to_divisions(bar.split('\n').filter(|&x| !x.is_empty()).collect(), offset);
+            bar_to_divisions(bar.split('\n').filter(|&x| !x.is_empty()).collect(), offset, &directions);
         notes.extend(divisions);
         offset += NOTE_UNIT;
     }
+    let measures = measure_starts(time_signatures, notes.last().map_or(0, |d| d.offset));
     // calc freeze end timing
     let mut notes_with_freeze_end: Vec<Division> = Vec::new();
     for div in &notes {
@@ -97,15 +220,22 @@ fn str_to_notes(bars: Vec<&str>, bpms: &[Bpm], stops: &[Stop]) -> Vec<Division>
         let mut arrows: Vec<Arrow> = Vec::new();
         for arrow in &div.arrows {
             let mut end = 0;
-            if arrow.arrow_type == ArrowType::Freeze {
-                end = find_freeze_end(&notes, div.offset, arrow.direction);
+            let mut arrow_type = arrow.arrow_type;
+            if arrow.is_hold() {
+                // 終点の無いfreezeはタップとして扱う(lintで報告する)
+                match find_freeze_end(&notes, div.offset, arrow.column) {
+                    Some(offset) => end = offset,
+                    None => arrow_type = ArrowType::Normal,
+                }
             }
             if arrow.arrow_type != ArrowType::FreezeEnd {
                 arrows.push(Arrow {
                     direction: arrow.direction,
-                    arrow_type: arrow.arrow_type,
+                    column: arrow.column,
+                    arrow_type,
                     end,
                     end_time: offset_to_time(end, bpms, stops),
+                    foot: None,
                 });
             }
         }
@@ -116,12 +246,75 @@ fn str_to_notes(bars: Vec<&str>, bpms: &[Bpm], stops: &[Stop]) -> Vec<Division>
                 color: div.color,
                 offset: div.offset,
                 time: offset_to_time(div.offset, bpms, stops),
+                measure: measure_of(&measures, div.offset),
             });
         }
     }
     notes_with_freeze_end
 }
 
+// offsetごとの行("1020"など)を、1小節192行の.sm形式の小節に変換する
+pub fn rows_to_bars(rows: &BTreeMap<i32, String>, width: usize) -> Vec<String> {
+    let num_bars = rows.keys().last().map_or(1, |ofs| ofs / NOTE_UNIT + 1);
+    let empty = "0".repeat(width);
+    (0..num_bars)
+        .map(|bar| {
+            (0..NOTE_UNIT)
+                .map(|i| rows.get(&(bar * NOTE_UNIT + i)).unwrap_or(&empty).as_str())
+                .collect::<Vec<&str>>()
+                .join("\n")
+        })
+        .collect()
+}
+
+// 各形式のパーサーが解析したノーツからChartを組み立てる
+pub fn make_chart(chart_type: ChartType, difficulty: Difficulty, level: i32, mut notes: Vec<Division>, bpms: &[Bpm], stops: &[Stop], time_signatures: &[TimeSignature]) -> Chart {
+    let parity = if chart_type == ChartType::DanceSingle { Some(solve_parity(&mut notes)) } else { None };
+    let patterns = find_patterns(&notes, chart_type);
+    let groove_radar = get_groove_radar(&notes, bpms, stops, &MusicLength::default(), RadarEra::X);
+    let stream_info = get_stream_info(&notes, time_signatures);
+    let density = get_density(&notes, bpms, stops, time_signatures, &DensityConfig::default());
+    let info = ChartInfo {
+        chart_type,
+        difficulty,
+        level,
+        max_combo: max_combo(&notes, ComboRule::Ddr),
+        stream: groove_radar.stream,
+        voltage: groove_radar.voltage,
+        air: groove_radar.air,
+        freeze: groove_radar.freeze,
+        chaos: groove_radar.chaos,
+        stream_measures: stream_info.stream_measures,
+        stream_density: stream_info.stream_density,
+        steps: count_steps(&notes),
+        hash: model_hash(chart_type, &notes, bpms, stops),
+        groovestats_hash: None,
+        parity,
+        patterns: PatternCounts::from_occurrences(&patterns),
+        scroll_speed: get_scroll_speed(&notes, bpms, DEFAULT_READ_SPEED),
+        rates: Vec::new(),
+        transforms: Vec::new(),
+    };
+    Chart {
+        info,
+        content: LegacyChartContent {
+            stream: notes,
+            stream_info,
+            density,
+            groove_radar: None,
+            patterns,
+            gimmick: Gimmick {
+                soflan: bpms.iter().cloned().map(BpmDisplay::from_bpm).collect(),
+                stop: stops.iter().cloned().map(StopDisplay::from_stop).collect(),
+                time_signature: time_signatures.iter().cloned().map(TimeSignatureDisplay::from_time_signature).collect(),
+            }
+        },
+        bpms: bpms.to_vec(),
+        stops: stops.to_vec(),
+        time_signatures: time_signatures.to_vec(),
+        music_length: MusicLength::default(),
+    }
+}
 
 pub fn offset_to_time(offset: i32, bpms: &[Bpm], stops: &[Stop]) -> f32 {
     let mut time = 0.0;
@@ -135,7 +328,7 @@ pub fn offset_to_time(offset: i32, bpms: &[Bpm], stops: &[Stop]) -> f32 {
         }
         let elapsed = ((bpm.offset - done) as f32) / ((NOTE_UNIT / 4) as f32);
         time += 60.0 / prev_bpm.bpm * elapsed;
-        done = bpm.offset as i32;
+        done = bpm.offset;
         prev_bpm = bpm;
     }
     if bpms[bpms.len() - 1].offset < offset {
@@ -150,6 +343,23 @@ pub fn offset_to_time(offset: i32, bpms: &[Bpm], stops: &[Stop]) -> f32 {
     }
     time
 }
+fn parse_time_signatures(s: Option<&String>) -> Vec<TimeSignature> {
+    match s {
+        Some(s) => s
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .filter_map(|s| match TimeSignature::from_str(s.trim()) {
+                Ok(t) => Some(t),
+                Err(e) => {
+                    println!("ignore time signature {}: {}", s, e);
+                    None
+                }
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
 struct ChartProps {
     props: HashMap<String, String>,
     note_strings: String,
@@ -180,13 +390,32 @@ pub fn create_chart(filepath: &String) -> Vec<Chart> {
         sm_to_chart(filepath)
     } else if filepath.ends_with(".ssc") {
         ssc_to_chart(filepath)
+    } else if filepath.ends_with(".dwi") {
+        dwi_to_chart(filepath)
+    } else if filepath.ends_with(".ksf") {
+        ksf_to_chart(filepath)
+    } else if filepath.ends_with(".osu") {
+        osu_to_chart(filepath)
+    } else if filepath.ends_with(".bms") || filepath.ends_with(".bme") || filepath.ends_with(".bhe") {
+        bms_to_chart(filepath)
     } else {
         panic!("unsupported file format");
     }
 }
 
+// 読み込み済みの.sm/.sscの内容から譜面を作る
+pub fn simfile_to_chart(contents: &str, format: Format) -> Vec<Chart> {
+    match format {
+        Format::Sm => sm_str_to_chart(contents),
+        Format::Ssc => ssc_str_to_chart(contents),
+    }
+}
+
 fn ssc_to_chart(filepath: &String) -> Vec<Chart> {
-    let contents = fs::read_to_string(filepath).expect("file open error");
+    ssc_str_to_chart(&fs::read_to_string(filepath).expect("file open error"))
+}
+
+fn ssc_str_to_chart(contents: &str) -> Vec<Chart> {
     // remove comment
     let statements_without_comment: Vec<&str> = contents
         .split('\n')
@@ -213,10 +442,10 @@ fn ssc_to_chart(filepath: &String) -> Vec<Chart> {
        chart_props.push(chart);
     }
 
-    return 
-       chart_props 
+    chart_props
         .iter()
-        .filter(|c| ChartType::from_str(c.props.get("STEPSTYPE").unwrap()).unwrap() == ChartType::DanceSingle)
+        // 対応していない種類(dance-coupleなど)は読まない
+        .filter(|c| ChartType::from_str(c.props.get("STEPSTYPE").unwrap()).is_ok())
         .map(|c| {
             let chart_type = ChartType::from_str(c.props.get("STEPSTYPE").unwrap()).unwrap();
             let difficulty = Difficulty::from_str(c.props.get("DIFFICULTY").unwrap()).unwrap();
@@ -227,7 +456,8 @@ fn ssc_to_chart(filepath: &String) -> Vec<Chart> {
             };
             // unwrap_orを使いたいけどstr周りのエラーがなんもわからん
             //let stop_str = props.get("STOPS").unwrap_or("".to_string());
-            let stop_str = if let Some(s) = c.props.get("STOPS") {
+            // BPMSと同じく譜面に無ければ曲の値を使う
+            let stop_str = if let Some(s) = c.props.get("STOPS").or_else(|| common_props.get("STOPS")) {
                 s
             } else {
                 ""
@@ -240,42 +470,29 @@ fn ssc_to_chart(filepath: &String) -> Vec<Chart> {
                     .map(|s| Stop::from_str(s.trim_end()).unwrap())
                     .collect()
             };
+            let time_signatures = parse_time_signatures(c.props.get("TIMESIGNATURES").or_else(|| common_props.get("TIMESIGNATURES")));
             let level = c.props.get("METER").unwrap().parse().unwrap();
             let notes = str_to_notes(
                 c.note_strings.split(',').map(|s| s.trim_start()).collect(),
+                chart_type,
                 &bpms,
                 &stops,
+                &time_signatures,
             );
-            let groove_radar = get_groove_radar(&notes, &bpms, &stops);
-            let info = ChartInfo {
-                chart_type,
-                difficulty,
-                level,
-                max_combo: notes.len() as i32,
-                stream: groove_radar.stream,
-                voltage: groove_radar.voltage,
-                air: groove_radar.air,
-                freeze: groove_radar.freeze,
-                chaos: groove_radar.chaos,
-            };
-            Chart {
-                info,
-                content: LegacyChartContent {
-                    stream: notes,
-                    stream_info: Vec::new(),
-                    gimmick: Gimmick {
-                        soflan: bpms.clone().into_iter().map(BpmDisplay::from_bpm).collect(),
-                        stop: stops.clone().into_iter().map(StopDisplay::from_stop).collect(),
-                    }
-                },
-            }
+            let mut chart = make_chart(chart_type, difficulty, level, notes, &bpms, &stops, &time_signatures);
+            let bpm_str = c.props.get("BPMS").or_else(|| common_props.get("BPMS")).unwrap();
+            chart.info.groovestats_hash = Some(groovestats_hash(&c.note_strings, bpm_str));
+            chart
         })
-        .collect();
+        .collect()
 }
 
 
 fn sm_to_chart(filepath: &String) -> Vec<Chart> {
-    let contents = fs::read_to_string(filepath).expect("file open error");
+    sm_str_to_chart(&fs::read_to_string(filepath).expect("file open error"))
+}
+
+fn sm_str_to_chart(contents: &str) -> Vec<Chart> {
     // remove comment
     let statements_without_comment: Vec<&str> = contents
         .split('\n')
@@ -321,47 +538,63 @@ fn sm_to_chart(filepath: &String) -> Vec<Chart> {
             .map(|s| Stop::from_str(s.trim_end()).unwrap())
             .collect()
     };
+    let time_signatures = parse_time_signatures(props.get("TIMESIGNATURES"));
     let notes_content: Vec<Vec<&str>> = notes_strings
         .iter()
         .map(|s| s.split(':').collect())
         .collect();
 
-    return 
-        notes_content
+    notes_content
         .iter()
-        .filter(|s| ChartType::from_str(s[0].trim_start()).unwrap() == ChartType::DanceSingle)
+        // 対応していない種類(dance-coupleなど)は読まない
+        .filter(|s| ChartType::from_str(s[0].trim_start()).is_ok())
         .map(|s| {
             let chart_type = ChartType::from_str(s[0].trim_start()).unwrap();
             let difficulty = Difficulty::from_str(s[2].trim_start()).unwrap();
             let level = s[3].trim_start().parse().unwrap();
             let notes = str_to_notes(
                 s[5].split(',').map(|s| s.trim_start()).collect(),
+                chart_type,
                 &bpms,
                 &stops,
+                &time_signatures,
             );
-            let groove_radar = get_groove_radar(&notes, &bpms, &stops);
-            let info = ChartInfo {
-                chart_type,
-                difficulty,
-                level,
-                max_combo: notes.len() as i32,
-                stream: groove_radar.stream,
-                voltage: groove_radar.voltage,
-                air: groove_radar.air,
-                freeze: groove_radar.freeze,
-                chaos: groove_radar.chaos,
-            };
-            Chart {
-                info,
-                content: LegacyChartContent {
-                    stream: notes,
-                    stream_info: Vec::new(),
-                    gimmick: Gimmick {
-                        soflan: bpms.clone().into_iter().map(BpmDisplay::from_bpm).collect(),
-                        stop: stops.clone().into_iter().map(StopDisplay::from_stop).collect(),
-                    }
-                },
-            }
+            let mut chart = make_chart(chart_type, difficulty, level, notes, &bpms, &stops, &time_signatures);
+            chart.info.groovestats_hash = Some(groovestats_hash(s[5], props.get("BPMS").unwrap()));
+            chart
         })
-        .collect();
+        .collect()
+}
+
+#[test]
+fn test_create_chart_double() {
+    let path = std::env::temp_dir().join(format!("sm_to_json_double_{}.sm", std::process::id()));
+    let bar = "10000001\n01110000\n00000000\n00000000";
+    let sm = format!(
+        "#BPMS:0.000=150.000;\n#NOTES:\n dance-single:\n:\n Hard:\n 5:\n 0,0,0,0,0:\n1000\n0100\n0000\n0000\n;\n\
+         #NOTES:\n dance-couple:\n:\n Hard:\n 5:\n 0,0,0,0,0:\n{}\n;\n\
+         #NOTES:\n dance-double:\n:\n Hard:\n 7:\n 0,0,0,0,0:\n{}\n;\n",
+        bar, bar
+    );
+    std::fs::write(&path, sm).unwrap();
+    let charts = create_chart(&path.to_str().unwrap().to_string());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(charts.iter().map(|c| c.info.chart_type).collect::<Vec<_>>(), vec![ChartType::DanceSingle, ChartType::DanceDouble]);
+    let double = &charts[1];
+    assert_eq!(double.info.steps.jumps, 2);
+    assert_eq!(double.notes()[0].arrows.iter().map(|a| a.column).collect::<Vec<_>>(), vec![0, 7]);
+}
+
+#[test]
+fn test_ssc_chart_timing() {
+    let path = std::env::temp_dir().join(format!("sm_to_json_timing_{}.ssc", std::process::id()));
+    let ssc = "#VERSION:0.83;\n#BPMS:0.000=150.000;\n#STOPS:4.000=0.500;\n\
+               #NOTEDATA:;\n#STEPSTYPE:dance-single;\n#DIFFICULTY:Hard;\n#METER:9;\n#BPMS:0.000=200.000;\n#NOTES:\n1000\n0100\n0010\n0001\n,\n1000\n0000\n0000\n0000\n;\n";
+    std::fs::write(&path, ssc).unwrap();
+    let charts = create_chart(&path.to_str().unwrap().to_string());
+    std::fs::remove_file(&path).unwrap();
+    // BPMSは譜面の値、STOPSは譜面に無いので曲の値を使う
+    assert_eq!(charts[0].bpms[0].bpm, 200.0);
+    assert_eq!(charts[0].stops.len(), 1);
+    assert_eq!(charts[0].stops[0].offset, NOTE_UNIT);
 }
@@ -0,0 +1,110 @@
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+
+use crate::arrow::{ArrowType, Division};
+use crate::chart::{ChartType, Difficulty};
+use crate::gimmick::{Bpm, Stop};
+
+// 譜面を識別するためのハッシュ
+// groovestats_hash: GrooveStats/ITGmania(Simply Love)と同じく、最小化したNOTESと正規化したBPMSのSHA-1の先頭16桁(.sm/.sscのみ)
+// hash: 読み込んだ後のノーツ、BPM、停止から求めるSHA-1。ファイル形式や書き方によらない
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// 奇数行がすべて空なら偶数行だけにする、を繰り返す
+fn minimize_measure(mut measure: Vec<&str>) -> Vec<&str> {
+    while measure.len() > 1 && measure.len().is_multiple_of(2) && measure.iter().skip(1).step_by(2).all(|row| row.chars().all(|c| c == '0')) {
+        measure = measure.into_iter().step_by(2).collect();
+    }
+    measure
+}
+
+fn minimize_chart(notes: &str) -> String {
+    let without_comments = notes.lines().map(|l| l.split("//").next().unwrap()).collect::<Vec<&str>>().join("\n");
+    let without_whitespace: String = without_comments.chars().filter(|c| !" \r\t\x0b\x0c;".contains(*c)).collect();
+    without_whitespace
+        .split(',')
+        .map(|m| minimize_measure(m.lines().filter(|l| !l.is_empty()).collect()).join("\n"))
+        .collect::<Vec<String>>()
+        .join("\n,\n")
+}
+
+// 小数点以下3桁に丸める
+fn normalize_bpms(bpms: &str) -> String {
+    let normalize = |s: &str| {
+        let value = s.chars().filter(|c| !c.is_control()).collect::<String>().trim().parse::<f64>().unwrap_or(0.0);
+        format!("{:.3}", (value * 1000.0 + 0.5).floor() / 1000.0)
+    };
+    bpms.split(',')
+        .filter_map(|s| s.split_once('='))
+        .map(|(beat, bpm)| format!("{}={}", normalize(beat), normalize(bpm)))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+pub fn groovestats_hash(notes: &str, bpms: &str) -> String {
+    let digest = Sha1::digest(format!("{}{}", minimize_chart(notes), normalize_bpms(bpms)).as_bytes());
+    to_hex(&digest)[..16].to_string()
+}
+
+pub fn model_hash(chart_type: ChartType, notes: &[Division], bpms: &[Bpm], stops: &[Stop]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{:?}\n", chart_type));
+    for division in notes {
+        for arrow in division.arrows.iter().filter(|a| a.arrow_type != ArrowType::FreezeEnd) {
+            let end = if arrow.is_hold() { format!("-{}", arrow.end) } else { String::new() };
+            hasher.update(format!("{}{}:{}:{:?}\n", division.offset, end, arrow.column, arrow.arrow_type));
+        }
+    }
+    for bpm in bpms {
+        hasher.update(format!("bpm {}={:.3}\n", bpm.offset, bpm.bpm));
+    }
+    for stop in stops {
+        hasher.update(format!("stop {}={:.3}\n", stop.offset, stop.time));
+    }
+    to_hex(&hasher.finalize())
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChartLocation {
+    pub dir_name: String,
+    pub file: String,
+    pub chart_type: ChartType,
+    pub difficulty: Difficulty,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DuplicateCharts {
+    pub hash: String,
+    pub charts: Vec<ChartLocation>,
+}
+
+// 別の曲ディレクトリに同じ譜面があるものを集める
+pub fn find_duplicates(charts: Vec<(String, ChartLocation)>) -> Vec<DuplicateCharts> {
+    let mut by_hash: BTreeMap<String, Vec<ChartLocation>> = BTreeMap::new();
+    for (hash, location) in charts {
+        by_hash.entry(hash).or_default().push(location);
+    }
+    by_hash
+        .into_iter()
+        .filter(|(_, charts)| charts.iter().any(|c| c.dir_name != charts[0].dir_name))
+        .map(|(hash, charts)| DuplicateCharts { hash, charts })
+        .collect()
+}
+
+#[test]
+fn test_groovestats_hash() {
+    // 書き方(余分な行、空白、コメント、BPMの桁数)が違っても同じハッシュになる
+    let a = groovestats_hash("1000\n0000\n0100\n0000\n,\n0000\n0000\n0000\n0000\n", "0.000=120.000");
+    let b = groovestats_hash(
+        "  1000 // first\n0000\n0000\n0000\n0100\n0000\n0000\n0000\n,\n0000\n0000\n0000\n0000",
+        "0=120.0001",
+    );
+    assert_eq!(a, b);
+    assert_eq!(minimize_chart("1000\n0000\n0100\n0000\n,\n0000\n0000\n0000\n0000"), "1000\n0100\n,\n0000");
+    assert_eq!(a, to_hex(&Sha1::digest(b"1000\n0100\n,\n00000.000=120.000"))[..16]);
+    assert_ne!(a, groovestats_hash("1000\n0000\n0100\n0000", "0.000=150.000"));
+}
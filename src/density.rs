@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+
+use crate::arrow::Division;
+use crate::chart::offset_to_time;
+use crate::gimmick::{Bpm, Stop, TimeSignature, measure_ranges};
+
+// 1秒あたりのノーツ数(NPS)。同時押しは1つと数え、地雷だけの行は数えない
+// 時間はDivision.timeを使うので停止やBPM変化も反映される
+
+pub const DEFAULT_WINDOW: f32 = 1.0;
+pub const DEFAULT_SUSTAINED_WINDOWS: [f32; 3] = [2.0, 5.0, 10.0];
+
+#[derive(Clone, Debug)]
+pub struct DensityConfig {
+    // per_windowの区間の長さ(秒)
+    pub window: f32,
+    // 持続NPSを求める区間の長さ(秒)
+    pub sustained_windows: Vec<f32>,
+}
+
+impl Default for DensityConfig {
+    fn default() -> Self {
+        DensityConfig {
+            window: DEFAULT_WINDOW,
+            sustained_windows: DEFAULT_SUSTAINED_WINDOWS.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SustainedNps {
+    pub window: f32,
+    pub nps: f32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Density {
+    // 小節ごとのNPS
+    pub per_measure: Vec<f32>,
+    pub window: f32,
+    // 0秒からwindow秒ごとのNPS
+    pub per_window: Vec<f32>,
+    // 小節ごとのNPSの最大
+    pub peak_nps: f32,
+    // window秒の間のNPSの最大
+    pub peak_sustained: Vec<SustainedNps>,
+}
+
+fn note_times(notes: &[Division]) -> Vec<f32> {
+    notes
+        .iter()
+        .filter(|d| d.arrows.iter().any(|a| a.is_note()))
+        .map(|d| d.time)
+        .collect()
+}
+
+// 尺取り法で、window秒以内に入るノーツ数の最大を求める
+fn peak_sustained(times: &[f32], window: f32) -> f32 {
+    let mut max = 0;
+    let mut start = 0;
+    for end in 0..times.len() {
+        while times[end] - times[start] >= window {
+            start += 1;
+        }
+        max = max.max(end - start + 1);
+    }
+    max as f32 / window
+}
+
+pub fn get_density(notes: &[Division], bpms: &[Bpm], stops: &[Stop], time_signatures: &[TimeSignature], config: &DensityConfig) -> Density {
+    let times = note_times(notes);
+    let last = notes.last().map_or(0, |d| d.offset);
+    let ranges = measure_ranges(time_signatures, last);
+    // ノーツも小節も順に並んでいるので、先頭から1回たどるだけで小節ごとに数える
+    let offsets: Vec<i32> = notes.iter().filter(|d| d.arrows.iter().any(|a| a.is_note())).map(|d| d.offset).collect();
+    let mut next = 0;
+    let mut per_measure = vec![0.0; ranges.len()];
+    for (i, (start, end)) in ranges.iter().enumerate() {
+        while next < offsets.len() && offsets[next] < *start {
+            next += 1;
+        }
+        let first = next;
+        while next < offsets.len() && offsets[next] < *end {
+            next += 1;
+        }
+        let count = next - first;
+        let duration = offset_to_time(*end, bpms, stops) - offset_to_time(*start, bpms, stops);
+        if duration > 0.0 {
+            per_measure[i] = count as f32 / duration;
+        }
+    }
+
+    let num_windows = times.last().map_or(0, |t| (t / config.window).floor().max(0.0) as usize + 1);
+    let mut per_window = vec![0.0; num_windows];
+    for t in &times {
+        if *t >= 0.0 {
+            per_window[(t / config.window) as usize] += 1.0 / config.window;
+        }
+    }
+
+    Density {
+        peak_nps: per_measure.iter().cloned().fold(0.0, f32::max),
+        per_measure,
+        window: config.window,
+        per_window,
+        peak_sustained: config
+            .sustained_windows
+            .iter()
+            .map(|w| SustainedNps { window: *w, nps: peak_sustained(&times, *w) })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_peak_sustained() {
+    // 0.1秒間隔で10個、その後1秒間隔
+    let mut times: Vec<f32> = (0..10).map(|i| i as f32 * 0.1).collect();
+    times.extend((1..5).map(|i| 1.0 + i as f32));
+    assert_eq!(peak_sustained(&times, 1.0), 10.0);
+    assert_eq!(peak_sustained(&times, 2.0), 5.0);
+}
+
+#[test]
+fn test_per_measure() {
+    use crate::chart::{ChartType, str_to_notes};
+    let bpms = [Bpm { offset: 0, bpm: 120.0 }];
+    let time_signatures = [TimeSignature { offset: 0, numerator: 4, denominator: 4 }];
+    // 120BPMの1小節は2秒。4分、空、8分(地雷だけの行は数えない)
+    let eighths = "1000\n0100\n0010\n0001\n1000\n0100\n0010\nM000";
+    let notes = str_to_notes(vec!["1000\n0100\n0010\n0001", "0000", eighths], ChartType::DanceSingle, &bpms, &[], &time_signatures);
+    let density = get_density(&notes, &bpms, &[], &time_signatures, &DensityConfig::default());
+    assert_eq!(density.per_measure, vec![2.0, 0.0, 3.5]);
+    assert_eq!(density.peak_nps, 3.5);
+}
@@ -0,0 +1,351 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::arrow::{ArrowType, Direction, Division, NOTE_UNIT};
+use std::str::FromStr;
+
+use crate::chart::{Chart, ChartType, Difficulty};
+use crate::groove_radar::GrooveRadar;
+use crate::simfile::Simfile;
+
+// 2つの版の譜面をテキストではなく譜面の内容で比べる
+// ノーツは小節と拍で示し、同じ列・種類のノーツが1拍以内で動いたものは追加と削除ではなく移動とする
+
+// 移動とみなす距離
+const MOVE_WINDOW: i32 = NOTE_UNIT / 4;
+// タイミングは譜面ごとに比べるので、メタデータの比較からは除く
+const TIMING_KEYS: [&str; 12] =
+    ["BPMS", "STOPS", "FREEZES", "DELAYS", "WARPS", "TIMESIGNATURES", "TICKCOUNTS", "COMBOS", "SPEEDS", "SCROLLS", "FAKES", "LABELS"];
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ValueChange<T> {
+    pub old: Option<T>,
+    pub new: Option<T>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct MetadataChange {
+    pub key: String,
+    #[serde(flatten)]
+    pub change: ValueChange<String>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, PartialEq)]
+pub struct NoteRef {
+    pub measure: i32,
+    pub beat: f32,
+    pub column: usize,
+    pub direction: Direction,
+    #[serde(rename = "type")]
+    pub arrow_type: ArrowType,
+    // freeze/rollの長さ(拍)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<f32>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct NoteMove {
+    pub from: NoteRef,
+    pub to: NoteRef,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SegmentChange {
+    // "bpm", "stop", "time_signature"
+    pub kind: &'static str,
+    pub beat: f32,
+    #[serde(flatten)]
+    pub change: ValueChange<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RadarDelta {
+    pub stream: i32,
+    pub voltage: i32,
+    pub air: i32,
+    pub freeze: i32,
+    pub chaos: i32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChartDiff {
+    // "dance-single Hard" など
+    pub chart: String,
+    // "added", "removed", "changed"
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<ValueChange<i32>>,
+    pub added: Vec<NoteRef>,
+    pub removed: Vec<NoteRef>,
+    pub moved: Vec<NoteMove>,
+    pub timing: Vec<SegmentChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radar: Option<RadarDelta>,
+}
+
+impl ChartDiff {
+    fn is_empty(&self) -> bool {
+        self.level.is_none() && self.added.is_empty() && self.removed.is_empty() && self.moved.is_empty() && self.timing.is_empty() && self.radar.is_none()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SimfileDiff {
+    pub metadata: Vec<MetadataChange>,
+    pub charts: Vec<ChartDiff>,
+}
+
+impl SimfileDiff {
+    pub fn is_empty(&self) -> bool {
+        self.metadata.is_empty() && self.charts.is_empty()
+    }
+
+    // 人が読むための1行ずつの表示
+    pub fn to_lines(&self) -> Vec<String> {
+        let value = |v: &Option<String>| v.as_ref().map_or("(none)".to_string(), |v| format!("\"{}\"", v));
+        let note = |n: &NoteRef| {
+            let length = n.length.map_or(String::new(), |l| format!(" length {}", l));
+            format!("measure {} beat {}: {:?} {:?}{}", n.measure, n.beat, n.direction, n.arrow_type, length)
+        };
+        let mut lines = Vec::new();
+        for m in &self.metadata {
+            lines.push(format!("#{}: {} -> {}", m.key, value(&m.change.old), value(&m.change.new)));
+        }
+        for c in &self.charts {
+            if c.status != "changed" {
+                lines.push(format!("[{}] chart {}", c.chart, c.status));
+                continue;
+            }
+            if let Some(level) = &c.level {
+                lines.push(format!("[{}] level {} -> {}", c.chart, level.old.unwrap_or(0), level.new.unwrap_or(0)));
+            }
+            for s in &c.timing {
+                lines.push(format!("[{}] {} at beat {}: {} -> {}", c.chart, s.kind, s.beat, value(&s.change.old), value(&s.change.new)));
+            }
+            lines.extend(c.removed.iter().map(|n| format!("[{}] - {}", c.chart, note(n))));
+            lines.extend(c.added.iter().map(|n| format!("[{}] + {}", c.chart, note(n))));
+            lines.extend(c.moved.iter().map(|m| format!("[{}] ~ {} -> measure {} beat {}", c.chart, note(&m.from), m.to.measure, m.to.beat)));
+            if let Some(r) = &c.radar {
+                lines.push(format!(
+                    "[{}] radar stream {:+} voltage {:+} air {:+} freeze {:+} chaos {:+}",
+                    c.chart, r.stream, r.voltage, r.air, r.freeze, r.chaos
+                ));
+            }
+        }
+        lines
+    }
+}
+
+fn to_beat(offset: i32) -> f32 {
+    offset as f32 / (NOTE_UNIT / 4) as f32
+}
+
+fn note_refs(notes: &[Division]) -> Vec<(i32, NoteRef)> {
+    notes
+        .iter()
+        .flat_map(|d| {
+            d.arrows.iter().filter(|a| a.arrow_type != ArrowType::FreezeEnd).map(move |a| {
+                let note = NoteRef {
+                    measure: d.measure,
+                    beat: to_beat(d.offset),
+                    column: a.column,
+                    direction: a.direction,
+                    arrow_type: a.arrow_type,
+                    length: a.is_hold().then(|| to_beat(a.end - d.offset)),
+                };
+                (d.offset, note)
+            })
+        })
+        .collect()
+}
+
+fn diff_notes(old: &[Division], new: &[Division]) -> (Vec<NoteRef>, Vec<NoteRef>, Vec<NoteMove>) {
+    let (old, new) = (note_refs(old), note_refs(new));
+    let mut removed: Vec<(i32, NoteRef)> = old.iter().filter(|o| !new.contains(o)).cloned().collect();
+    let mut added: Vec<(i32, NoteRef)> = new.iter().filter(|n| !old.contains(n)).cloned().collect();
+    // 近いものから順に移動として対にする
+    let mut pairs: Vec<(i32, usize, usize)> = Vec::new();
+    for (i, (from_offset, from)) in removed.iter().enumerate() {
+        for (j, (to_offset, to)) in added.iter().enumerate() {
+            let distance = (to_offset - from_offset).abs();
+            if distance <= MOVE_WINDOW && from.column == to.column && from.arrow_type == to.arrow_type && from.length == to.length {
+                pairs.push((distance, i, j));
+            }
+        }
+    }
+    pairs.sort();
+    let (mut used_removed, mut used_added) = (vec![false; removed.len()], vec![false; added.len()]);
+    let mut moved = Vec::new();
+    for (_, i, j) in pairs {
+        if !used_removed[i] && !used_added[j] {
+            used_removed[i] = true;
+            used_added[j] = true;
+            moved.push(NoteMove { from: removed[i].1, to: added[j].1 });
+        }
+    }
+    let mut used = used_removed.iter();
+    removed.retain(|_| !used.next().unwrap());
+    let mut used = used_added.iter();
+    added.retain(|_| !used.next().unwrap());
+    (removed.into_iter().map(|n| n.1).collect(), added.into_iter().map(|n| n.1).collect(), moved)
+}
+
+fn diff_segments(kind: &'static str, old: BTreeMap<i32, String>, new: BTreeMap<i32, String>) -> Vec<SegmentChange> {
+    let mut offsets: Vec<&i32> = old.keys().chain(new.keys()).collect();
+    offsets.sort();
+    offsets.dedup();
+    offsets
+        .into_iter()
+        .filter(|o| old.get(o) != new.get(o))
+        .map(|o| SegmentChange { kind, beat: to_beat(*o), change: ValueChange { old: old.get(o).cloned(), new: new.get(o).cloned() } })
+        .collect()
+}
+
+fn diff_timing(old: &Chart, new: &Chart) -> Vec<SegmentChange> {
+    let bpms = |c: &Chart| c.bpms.iter().map(|b| (b.offset, b.bpm.to_string())).collect();
+    let stops = |c: &Chart| c.stops.iter().map(|s| (s.offset, s.time.to_string())).collect();
+    let time_signatures = |c: &Chart| c.time_signatures.iter().map(|t| (t.offset, format!("{}/{}", t.numerator, t.denominator))).collect();
+    let mut changes = diff_segments("bpm", bpms(old), bpms(new));
+    changes.extend(diff_segments("stop", stops(old), stops(new)));
+    changes.extend(diff_segments("time_signature", time_signatures(old), time_signatures(new)));
+    changes
+}
+
+fn radar_of(chart: &Chart) -> GrooveRadar {
+    GrooveRadar { stream: chart.info.stream, voltage: chart.info.voltage, air: chart.info.air, freeze: chart.info.freeze, chaos: chart.info.chaos }
+}
+
+fn diff_chart(name: String, old: &Chart, new: &Chart) -> ChartDiff {
+    let (removed, added, moved) = diff_notes(old.notes(), new.notes());
+    let (o, n) = (radar_of(old), radar_of(new));
+    let radar = RadarDelta { stream: n.stream - o.stream, voltage: n.voltage - o.voltage, air: n.air - o.air, freeze: n.freeze - o.freeze, chaos: n.chaos - o.chaos };
+    let zero = RadarDelta { stream: 0, voltage: 0, air: 0, freeze: 0, chaos: 0 };
+    ChartDiff {
+        chart: name,
+        status: "changed",
+        level: (old.info.level != new.info.level).then_some(ValueChange { old: Some(old.info.level), new: Some(new.info.level) }),
+        added,
+        removed,
+        moved,
+        timing: diff_timing(old, new),
+        radar: (radar != zero).then_some(radar),
+    }
+}
+
+fn chart_name(chart: &Chart) -> String {
+    format!("{:?} {:?}", chart.info.chart_type, chart.info.difficulty)
+}
+
+// Editは同じ種類に複数あることがあるので、説明(空か他と同じならその種類のEditの中での順番)で区別する
+// 譜面はファイルの順に並んでいるので、n番目のEditはタグの同じ種類のn番目のEditになる
+fn chart_names(simfile: &Simfile, charts: &[Chart]) -> Vec<String> {
+    let descriptions = |chart_type: ChartType| -> Vec<String> {
+        simfile
+            .charts
+            .iter()
+            .filter(|c| c.get("STEPSTYPE").and_then(|s| ChartType::from_str(s.trim()).ok()) == Some(chart_type))
+            .filter(|c| c.get("DIFFICULTY").map(|s| s.trim()) == Some("Edit"))
+            .map(|c| c.get("DESCRIPTION").filter(|s| !s.trim().is_empty()).or(c.get("CHARTNAME")).unwrap_or("").trim().to_string())
+            .collect()
+    };
+    let mut edit_counts: Vec<(ChartType, usize)> = Vec::new();
+    charts
+        .iter()
+        .map(|chart| {
+            let name = chart_name(chart);
+            if chart.info.difficulty != Difficulty::Edit {
+                return name;
+            }
+            let index = match edit_counts.iter_mut().find(|(t, _)| *t == chart.info.chart_type) {
+                Some((_, count)) => {
+                    *count += 1;
+                    *count - 1
+                }
+                None => {
+                    edit_counts.push((chart.info.chart_type, 1));
+                    0
+                }
+            };
+            let descriptions = descriptions(chart.info.chart_type);
+            match descriptions.get(index) {
+                Some(d) if !d.is_empty() && descriptions.iter().filter(|other| *other == d).count() == 1 => format!("{} \"{}\"", name, d),
+                _ => format!("{} #{}", name, index + 1),
+            }
+        })
+        .collect()
+}
+
+fn empty_chart_diff(name: String, status: &'static str) -> ChartDiff {
+    ChartDiff { chart: name, status, level: None, added: Vec::new(), removed: Vec::new(), moved: Vec::new(), timing: Vec::new(), radar: None }
+}
+
+pub fn diff_simfiles(old: &Simfile, old_charts: &[Chart], new: &Simfile, new_charts: &[Chart]) -> SimfileDiff {
+    let mut keys: Vec<&str> = old.header.iter().chain(new.header.iter()).map(|t| t.key.as_str()).filter(|k| !TIMING_KEYS.contains(k)).collect();
+    keys.sort();
+    keys.dedup();
+    let metadata = keys
+        .into_iter()
+        .filter(|k| old.get(k) != new.get(k))
+        .map(|k| MetadataChange { key: k.to_string(), change: ValueChange { old: old.get(k).map(|s| s.to_string()), new: new.get(k).map(|s| s.to_string()) } })
+        .collect();
+
+    // 種類と難易度(Editは説明か順番)が同じものを同じ譜面とみなす
+    let (old_names, new_names) = (chart_names(old, old_charts), chart_names(new, new_charts));
+    let mut charts = Vec::new();
+    for (name, old_chart) in old_names.iter().zip(old_charts) {
+        match new_names.iter().position(|n| n == name) {
+            Some(i) => charts.push(diff_chart(name.clone(), old_chart, &new_charts[i])),
+            None => charts.push(empty_chart_diff(name.clone(), "removed")),
+        }
+    }
+    for name in new_names.iter().filter(|n| !old_names.contains(n)) {
+        charts.push(empty_chart_diff(name.clone(), "added"));
+    }
+    charts.retain(|c| c.status != "changed" || !c.is_empty());
+    SimfileDiff { metadata, charts }
+}
+
+#[test]
+fn test_diff_simfiles() {
+    use crate::chart::{make_chart, str_to_notes, ChartType, Difficulty};
+    use crate::gimmick::{Bpm, TimeSignature};
+    use crate::simfile::Format;
+    let chart = |bars: Vec<&str>, bpm: f32| {
+        let bpms = vec![Bpm { offset: 0, bpm }];
+        let time_signatures = vec![TimeSignature { offset: 0, numerator: 4, denominator: 4 }];
+        let notes = str_to_notes(bars, ChartType::DanceSingle, &bpms, &[], &time_signatures);
+        make_chart(ChartType::DanceSingle, Difficulty::Hard, 9, notes, &bpms, &[], &time_signatures)
+    };
+    let old = [chart(vec!["1000\n0100\n0010\n0001", "1000\n0000\n0000\n0000"], 120.0)];
+    let new = [chart(vec!["1000\n0000\n0100\n0001", "0000\n0000\n0000\n0000"], 150.0)];
+    let diff = diff_simfiles(
+        &Simfile::parse("#TITLE:a;", Format::Sm),
+        &old,
+        &Simfile::parse("#TITLE:b;", Format::Sm),
+        &new,
+    );
+    assert_eq!(diff.metadata, vec![MetadataChange { key: "TITLE".to_string(), change: ValueChange { old: Some("a".to_string()), new: Some("b".to_string()) } }]);
+    let c = &diff.charts[0];
+    // 2拍目の下が3拍目に移動、3拍目の上が消え、2小節目の左が消えた
+    assert_eq!(c.moved.len(), 1);
+    assert_eq!((c.moved[0].from.beat, c.moved[0].to.beat), (1.0, 2.0));
+    assert_eq!(c.removed.iter().map(|n| (n.measure, n.beat)).collect::<Vec<_>>(), vec![(0, 2.0), (1, 4.0)]);
+    assert!(c.added.is_empty());
+    assert_eq!(c.timing, vec![SegmentChange { kind: "bpm", beat: 0.0, change: ValueChange { old: Some("120".to_string()), new: Some("150".to_string()) } }]);
+
+    // Editは説明で対応させる
+    let edit = |bars: Vec<&str>| {
+        let bpms = vec![Bpm { offset: 0, bpm: 120.0 }];
+        let time_signatures = vec![TimeSignature { offset: 0, numerator: 4, denominator: 4 }];
+        let notes = str_to_notes(bars, ChartType::DanceSingle, &bpms, &[], &time_signatures);
+        make_chart(ChartType::DanceSingle, Difficulty::Edit, 9, notes, &bpms, &[], &time_signatures)
+    };
+    let edits = |first: &str, second: &str| {
+        Simfile::parse(&format!("#NOTES:dance-single:{}:Edit:9::1000;\n#NOTES:dance-single:{}:Edit:9::1000;", first, second), Format::Sm)
+    };
+    let (a, b) = (edit(vec!["1000\n0000\n0000\n0000"]), edit(vec!["0001\n0000\n0000\n0000"]));
+    let swapped = diff_simfiles(&edits("A", "B"), &[a, b], &edits("B", "A"), &[edit(vec!["0001\n0000\n0000\n0000"]), edit(vec!["1000\n0000\n0000\n0000"])]);
+    assert!(swapped.is_empty());
+    let unnamed = diff_simfiles(&edits("", ""), &[edit(vec!["1000"])], &edits("", ""), &[edit(vec!["1000"]), edit(vec!["0100"])]);
+    assert_eq!(unnamed.charts.iter().map(|c| (c.chart.as_str(), c.status)).collect::<Vec<_>>(), vec![("DanceSingle Edit #2", "added")]);
+}
@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::arrow::NOTE_UNIT;
+use crate::chart::{Chart, ChartType, Difficulty, make_chart, rows_to_bars, str_to_notes};
+use crate::gimmick::{Bpm, Stop};
+use crate::simfile::{remove_comments, split_tags};
+
+// DWI(Dance With Intensity)形式
+// 1文字が1行(デフォルトは8分)で、括弧で囲むと細かい音符になる
+// CHANGEBPMとFREEZEの位置は16分単位、FREEZEの長さとGAPはミリ秒
+// SINGLEだけを読み、DOUBLE、COUPLE、SOLOの譜面は警告を出して読まない
+
+const EIGHTH: i32 = NOTE_UNIT / 8;
+
+fn parse_difficulty(s: &str) -> Result<Difficulty, String> {
+    match s.trim() {
+        "BEGINNER" => Ok(Difficulty::Beginner),
+        "BASIC" => Ok(Difficulty::Easy),
+        "ANOTHER" => Ok(Difficulty::Medium),
+        "MANIAC" => Ok(Difficulty::Hard),
+        "SMANIAC" => Ok(Difficulty::Challenge),
+        _ => Err(format!("{} is not a DWI difficulty", s)),
+    }
+}
+
+// 左下上右の順
+fn step_to_columns(c: char) -> Result<[bool; 4], String> {
+    match c {
+        '0' => Ok([false, false, false, false]),
+        '1' => Ok([true, true, false, false]),
+        '2' => Ok([false, true, false, false]),
+        '3' => Ok([false, true, false, true]),
+        '4' => Ok([true, false, false, false]),
+        '6' => Ok([false, false, false, true]),
+        '7' => Ok([true, false, true, false]),
+        '8' => Ok([false, false, true, false]),
+        '9' => Ok([false, false, true, true]),
+        'A' => Ok([false, true, true, false]),
+        'B' => Ok([true, false, false, true]),
+        _ => Err(format!("{} is not a supported DWI step", c)),
+    }
+}
+
+fn bracket_to_increment(c: char) -> Option<i32> {
+    match c {
+        '(' => Some(NOTE_UNIT / 16),
+        '[' => Some(NOTE_UNIT / 24),
+        '{' => Some(NOTE_UNIT / 64),
+        '`' => Some(NOTE_UNIT / 192),
+        ')' | ']' | '}' | '\'' => Some(EIGHTH),
+        _ => None,
+    }
+}
+
+// <24> のような同時押しもまとめて1行として読む
+fn read_row(chars: &[char], i: &mut usize) -> Result<[bool; 4], String> {
+    let mut columns = [false; 4];
+    if chars[*i] == '<' {
+        *i += 1;
+        while *i < chars.len() && chars[*i] != '>' {
+            let step = step_to_columns(chars[*i])?;
+            for (c, s) in columns.iter_mut().zip(step) {
+                *c |= s;
+            }
+            *i += 1;
+        }
+    } else {
+        columns = step_to_columns(chars[*i])?;
+    }
+    *i += 1;
+    Ok(columns)
+}
+
+// offsetごとの "1020" のような行に変換する。freezeは次に同じ列を踏んだところで終わる
+fn parse_steps(steps: &str) -> Result<BTreeMap<i32, String>, String> {
+    let chars: Vec<char> = steps.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut rows: BTreeMap<i32, String> = BTreeMap::new();
+    let mut holding = [false; 4];
+    let mut increment = EIGHTH;
+    let mut offset = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        if let Some(inc) = bracket_to_increment(chars[i]) {
+            increment = inc;
+            i += 1;
+            continue;
+        }
+        let columns = read_row(&chars, &mut i)?;
+        let freezes = if i < chars.len() && chars[i] == '!' {
+            i += 1;
+            read_row(&chars, &mut i)?
+        } else {
+            [false; 4]
+        };
+        let mut row = ['0'; 4];
+        for col in 0..4 {
+            if !columns[col] {
+                continue;
+            }
+            row[col] = if holding[col] {
+                holding[col] = false;
+                '3'
+            } else if freezes[col] {
+                holding[col] = true;
+                '2'
+            } else {
+                '1'
+            };
+        }
+        if row.iter().any(|&c| c != '0') {
+            rows.insert(offset, row.iter().collect());
+        }
+        offset += increment;
+    }
+    if holding.iter().any(|&h| h) {
+        return Err("freeze without end".to_string());
+    }
+    Ok(rows)
+}
+
+// "位置(16分単位)=値" のリスト
+fn parse_sixteenth_pairs(s: &str) -> Vec<(i32, f32)> {
+    s.split(',')
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut pair = pair.split('=');
+            let position = pair.next().unwrap().trim().parse::<f32>().unwrap();
+            let value = pair.next().unwrap().trim().parse::<f32>().unwrap();
+            ((position * (NOTE_UNIT / 16) as f32) as i32, value)
+        })
+        .collect()
+}
+
+pub fn dwi_to_chart(filepath: &String) -> Vec<Chart> {
+    let contents = fs::read_to_string(filepath).expect("file open error");
+    let tags = split_tags(&remove_comments(&contents));
+    let get = |key: &str| tags.iter().find(|t| t.key == key).map(|t| t.value.as_str());
+
+    let mut bpms = vec![Bpm {
+        offset: 0,
+        bpm: get("BPM").unwrap().parse().unwrap(),
+    }];
+    if let Some(s) = get("CHANGEBPM") {
+        bpms.extend(parse_sixteenth_pairs(s).into_iter().map(|(offset, bpm)| Bpm { offset, bpm }));
+    }
+    let stops: Vec<Stop> = match get("FREEZE") {
+        Some(s) => parse_sixteenth_pairs(s)
+            .into_iter()
+            .map(|(offset, ms)| Stop { offset, time: ms / 1000.0 })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    for tag in tags.iter().filter(|t| ["DOUBLE", "COUPLE", "SOLO"].contains(&t.key.as_str())) {
+        eprintln!("WARNING: {}: #{} charts are not supported; skipped", filepath, tag.key);
+    }
+    tags.iter()
+        .filter(|t| t.key == "SINGLE")
+        .filter_map(|t| {
+            let fields: Vec<&str> = t.value.splitn(3, ':').collect();
+            if fields.len() < 3 {
+                eprintln!("WARNING: {}: #SINGLE needs difficulty, level and steps; skipped", filepath);
+                return None;
+            }
+            let (difficulty, level) = match (parse_difficulty(fields[0]), fields[1].trim().parse()) {
+                (Ok(difficulty), Ok(level)) => (difficulty, level),
+                (Err(e), _) => {
+                    eprintln!("WARNING: {}: {}; skipped", filepath, e);
+                    return None;
+                }
+                (_, Err(e)) => {
+                    eprintln!("WARNING: {}: level {}: {}; skipped", filepath, fields[1].trim(), e);
+                    return None;
+                }
+            };
+            let rows = match parse_steps(fields[2]) {
+                Ok(rows) => rows,
+                Err(e) => {
+                    eprintln!("WARNING: {}: {:?}: {}; skipped", filepath, difficulty, e);
+                    return None;
+                }
+            };
+            let bars = rows_to_bars(&rows, 4);
+            let notes = str_to_notes(bars.iter().map(|s| s.as_str()).collect(), ChartType::DanceSingle, &bpms, &stops, &[]);
+            Some(make_chart(ChartType::DanceSingle, difficulty, level, notes, &bpms, &stops, &[]))
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_steps() {
+    // 8分2つ、16分で4つ、同時押しとfreeze
+    let rows = parse_steps("48(2A86)<46>0 7!7 8 4").unwrap();
+    assert_eq!(rows.get(&0), Some(&"1000".to_string()));
+    assert_eq!(rows.get(&24), Some(&"0010".to_string()));
+    assert_eq!(rows.get(&48), Some(&"0100".to_string()));
+    assert_eq!(rows.get(&60), Some(&"0110".to_string()));
+    assert_eq!(rows.get(&96), Some(&"1001".to_string()));
+    assert_eq!(rows.get(&144), Some(&"2020".to_string()));
+    assert_eq!(rows.get(&168), Some(&"0030".to_string()));
+    assert_eq!(rows.get(&192), Some(&"3000".to_string()));
+    assert!(parse_steps("8!8").is_err());
+}
+
+#[test]
+fn test_dwi_to_chart() {
+    let path = std::env::temp_dir().join(format!("sm_to_json_{}.dwi", std::process::id()));
+    let dwi = "#TITLE:test;\n#BPM:150;\n#SINGLE:BASIC:3:2468;\n#SINGLE:WILD:5:2468;\n#SINGLE:MANIAC:x:2468;\n#DOUBLE:BASIC:4:2468:2468;\n";
+    fs::write(&path, dwi).unwrap();
+    // 知らない難易度や読めないレベルの譜面とDOUBLEは読み飛ばす
+    let charts = dwi_to_chart(&path.to_str().unwrap().to_string());
+    fs::remove_file(&path).unwrap();
+    assert_eq!(charts.len(), 1);
+    assert_eq!(charts[0].info.difficulty, Difficulty::Easy);
+}
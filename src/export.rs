@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::assets::Assets;
+use crate::audio::wav_clip;
+
+// 音声と画像を譜面のjsonと同じディレクトリにコピーする
+// サブディレクトリや"../"にあるファイルもファイル名だけにしてコピーするので、songs.jsonのパスはファイル名になる
+
+// SAMPLELENGTHが無い場合のプレビューの長さ(StepManiaと同じ)
+const DEFAULT_SAMPLE_LENGTH: f32 = 12.0;
+
+#[derive(Clone, Debug, Default)]
+pub struct ExportOptions {
+    // バナーの縮小版の最大の幅と高さ
+    pub thumbnail: Option<(u32, u32)>,
+    // SAMPLESTART/SAMPLELENGTHの範囲を切り出す(wavのみ)
+    pub preview: bool,
+}
+
+// 出力先のディレクトリからの相対パス
+#[derive(Debug, Default)]
+pub struct ExportedAssets {
+    pub music: Option<String>,
+    pub banner: Option<String>,
+    pub background: Option<String>,
+    pub jacket: Option<String>,
+    pub banner_thumbnail: Option<String>,
+    pub preview: Option<String>,
+}
+
+fn copy_asset(src_dir: &Path, dest_dir: &Path, relative: &Option<String>) -> Option<String> {
+    let src = src_dir.join(relative.as_ref()?);
+    let name = src.file_name()?.to_str()?.to_string();
+    match fs::copy(&src, dest_dir.join(&name)) {
+        Ok(_) => Some(name),
+        Err(e) => {
+            eprintln!("WARNING: {}: {}", src.display(), e);
+            None
+        }
+    }
+}
+
+fn make_thumbnail(src: &Path, dest_dir: &Path, (width, height): (u32, u32)) -> Result<String, String> {
+    let image = image::open(src).map_err(|e| format!("{}: {}", src.display(), e))?;
+    let name = format!("{}-thumb.png", src.file_stem().and_then(|s| s.to_str()).unwrap_or("banner"));
+    // 縦横比を保ったままwidth x heightに収める
+    image
+        .thumbnail(width, height)
+        .save_with_format(dest_dir.join(&name), image::ImageFormat::Png)
+        .map_err(|e| format!("{}: {}", name, e))?;
+    Ok(name)
+}
+
+// 同じディレクトリに譜面ファイルが複数あっても重ならないように、譜面ファイルの名前を付ける
+fn make_preview(filepath: &str, src: &Path, dest_dir: &Path, props: &HashMap<String, String>) -> Result<String, String> {
+    let is_wav = src.extension().and_then(|e| e.to_str()).is_some_and(|e| e.eq_ignore_ascii_case("wav"));
+    if !is_wav {
+        return Err(format!("{}: preview clip needs a WAV source", src.display()));
+    }
+    let value = |key: &str| props.get(key).and_then(|s| s.trim().parse::<f32>().ok());
+    let start = value("SAMPLESTART").unwrap_or(0.0);
+    let length = value("SAMPLELENGTH").filter(|l| *l > 0.0).unwrap_or(DEFAULT_SAMPLE_LENGTH);
+    let data = fs::read(src).map_err(|e| format!("{}: {}", src.display(), e))?;
+    let clip = wav_clip(&data, start, length).ok_or(format!("{}: cannot read WAV data", src.display()))?;
+    let name = format!("{}-preview.wav", Path::new(filepath).file_stem().and_then(|s| s.to_str()).unwrap_or("song"));
+    fs::write(dest_dir.join(&name), clip).map_err(|e| format!("{}: {}", name, e))?;
+    Ok(name)
+}
+
+pub fn export_assets(filepath: &str, dest_dir: &Path, assets: &Assets, props: &HashMap<String, String>, options: &ExportOptions) -> ExportedAssets {
+    let src_dir = Path::new(filepath).parent().unwrap_or(Path::new(""));
+    let mut exported = ExportedAssets {
+        music: copy_asset(src_dir, dest_dir, &assets.music),
+        banner: copy_asset(src_dir, dest_dir, &assets.banner),
+        background: copy_asset(src_dir, dest_dir, &assets.background),
+        jacket: copy_asset(src_dir, dest_dir, &assets.jacket),
+        ..Default::default()
+    };
+    if let (Some(size), Some(banner)) = (options.thumbnail, &exported.banner) {
+        match make_thumbnail(&dest_dir.join(banner), dest_dir, size) {
+            Ok(name) => exported.banner_thumbnail = Some(name),
+            Err(e) => eprintln!("WARNING: {}", e),
+        }
+    }
+    if options.preview {
+        if let Some(music) = &exported.music {
+            match make_preview(filepath, &dest_dir.join(music), dest_dir, props) {
+                Ok(name) => exported.preview = Some(name),
+                Err(e) => eprintln!("WARNING: {}", e),
+            }
+        }
+    }
+    exported
+}
+
+#[test]
+fn test_thumbnail_and_preview() {
+    let dir = std::env::temp_dir().join(format!("sm_to_json_export_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // 縦横比を保って200x200に収める
+    image::RgbImage::new(400, 100).save(dir.join("banner.png")).unwrap();
+    let name = make_thumbnail(&dir.join("banner.png"), &dir, (200, 200)).unwrap();
+    assert_eq!(name, "banner-thumb.png");
+    assert_eq!(image::image_dimensions(dir.join(&name)).unwrap(), (200, 50));
+
+    // 8kHz 8bit モノラルで3秒のwavから1秒目から1.5秒を切り出す
+    let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0".to_vec();
+    wav.extend(8000u32.to_le_bytes());
+    wav.extend(8000u32.to_le_bytes());
+    wav.extend([1, 0, 8, 0]);
+    wav.extend(b"data");
+    wav.extend(24000u32.to_le_bytes());
+    wav.extend((0..24000).map(|i| (i / 8000) as u8));
+    fs::write(dir.join("song.wav"), &wav).unwrap();
+    let props = HashMap::from([("SAMPLESTART".to_string(), "1.0".to_string()), ("SAMPLELENGTH".to_string(), "1.5".to_string())]);
+    let name = make_preview("songs/x/Song.sm", &dir.join("song.wav"), &dir, &props).unwrap();
+    assert_eq!(name, "Song-preview.wav");
+    let clip = fs::read(dir.join(&name)).unwrap();
+    assert_eq!(&clip[..4], b"RIFF");
+    assert_eq!(u32::from_le_bytes(clip[40..44].try_into().unwrap()), 12000);
+    assert_eq!((clip[44], clip[44 + 11999]), (1, 2));
+    fs::remove_dir_all(&dir).unwrap();
+}
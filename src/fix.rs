@@ -0,0 +1,220 @@
+use crate::arrow::NOTE_UNIT;
+use crate::simfile::{Simfile, Tag};
+
+// lintで見つかる問題のうち、機械的に直せるものを直す
+// - 始点の無いfreeze/rollの終点を消す
+// - 終点の無いfreeze/rollをタップにする(freeze中の列で始まるfreeze/rollがあれば、先のfreeze/rollをタップにする)
+// - 小節を最小の行数に詰め直す(192を割り切れない行数の小節は一番近い位置に寄せる)
+// - 同じ拍のBPMと、直前と同じ値のBPMを消す
+// - 末尾の空の小節を消す
+// - DIFFICULTYとSTEPSTYPEの表記を揃える
+
+// StepManiaが書き出す行数(最小は4行)
+const ROW_COUNTS: [usize; 10] = [4, 8, 12, 16, 24, 32, 48, 64, 96, 192];
+
+// StepManiaが古い難易度名として受け付けるもの
+fn normalize_difficulty(s: &str) -> Option<&'static str> {
+    match s.to_lowercase().as_str() {
+        "beginner" => Some("Beginner"),
+        "easy" | "basic" | "light" => Some("Easy"),
+        "medium" | "another" | "trick" | "standard" | "difficult" => Some("Medium"),
+        "hard" | "ssr" | "maniac" | "heavy" => Some("Hard"),
+        "challenge" | "smaniac" | "expert" | "oni" => Some("Challenge"),
+        "edit" => Some("Edit"),
+        _ => None,
+    }
+}
+
+// 同じ拍のBPMは最初のものを残し、直前と同じBPMは消す。値の文字列はそのまま残す
+fn dedupe_bpms(value: &str) -> String {
+    let mut segments: Vec<(f64, f64, &str)> = value
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| {
+            let (beat, bpm) = s.split_once('=')?;
+            Some((beat.trim().parse().ok()?, bpm.trim().parse().ok()?, s))
+        })
+        .collect();
+    // 読めない項目があれば触らない
+    if segments.len() != value.split(',').filter(|s| !s.trim().is_empty()).count() {
+        return value.to_string();
+    }
+    segments.sort_by(|a, b| a.0.total_cmp(&b.0));
+    // 同じ拍に複数ある場合はStepManiaと同じく後のものを使う
+    let mut last_per_beat: Vec<(f64, f64, &str)> = Vec::new();
+    for segment in segments {
+        match last_per_beat.last_mut() {
+            Some(last) if last.0 == segment.0 => *last = segment,
+            _ => last_per_beat.push(segment),
+        }
+    }
+    let mut kept: Vec<(f64, f64, &str)> = Vec::new();
+    for segment in last_per_beat {
+        if kept.last().is_none_or(|last| last.1 != segment.1) {
+            kept.push(segment);
+        }
+    }
+    kept.iter().map(|s| s.2).collect::<Vec<&str>>().join(",")
+}
+
+type Measure = Vec<Vec<char>>;
+
+fn is_empty_row(row: &[char]) -> bool {
+    row.iter().all(|c| *c == '0')
+}
+
+// 行が192分割のどこにあるかを求め、すべての行が乗る最小の行数に詰め直す
+fn quantize(measure: &Measure, columns: usize, changes: &mut Vec<String>, name: &str) -> Measure {
+    let n = measure.len();
+    let mut positions: Vec<(usize, &Vec<char>)> = Vec::new();
+    for (i, row) in measure.iter().enumerate() {
+        if !is_empty_row(row) {
+            let position = ((i * NOTE_UNIT as usize * 2 + n) / (n * 2)).min(NOTE_UNIT as usize - 1);
+            positions.push((position, row));
+        }
+    }
+    let count = *ROW_COUNTS
+        .iter()
+        .find(|count| positions.iter().all(|(p, _)| p % (NOTE_UNIT as usize / **count) == 0))
+        .unwrap();
+    let mut quantized = vec![vec!['0'; columns]; count];
+    for (position, row) in positions {
+        let target = &mut quantized[position / (NOTE_UNIT as usize / count)];
+        for (column, c) in row.iter().enumerate() {
+            if *c == '0' {
+                continue;
+            }
+            if target[column] != '0' {
+                changes.push(format!("{}: notes snapped onto the same row were merged", name));
+                continue;
+            }
+            target[column] = *c;
+        }
+    }
+    quantized
+}
+
+// 譜面1つのNOTESを直す。行の長さが揃っていなければ何もしない
+fn fix_notes(notes: &str, name: &str, changes: &mut Vec<String>) -> Option<String> {
+    let mut measures: Vec<Measure> = notes
+        .split(',')
+        .map(|m| m.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).map(|l| l.chars().collect()).collect())
+        .collect();
+    let columns = measures.iter().flatten().next().map_or(0, |r: &Vec<char>| r.len());
+    if columns == 0 || measures.iter().flatten().any(|r| r.len() != columns) {
+        eprintln!("WARNING: {}: rows have different widths, notes left unchanged", name);
+        return None;
+    }
+
+    let mut held: Vec<Option<(usize, usize)>> = vec![None; columns];
+    // 終点の無いfreeze/rollの始点(列ごと)
+    let mut unterminated: Vec<(usize, usize, usize)> = Vec::new();
+    for (m, measure) in measures.iter_mut().enumerate() {
+        for (r, row) in measure.iter_mut().enumerate() {
+            for (column, c) in row.iter_mut().enumerate() {
+                match *c {
+                    '2' | '4' => {
+                        if let Some((hm, hr)) = held[column].replace((m, r)) {
+                            unterminated.push((hm, hr, column));
+                        }
+                    }
+                    '3' if held[column].take().is_none() => {
+                        *c = '0';
+                        changes.push(format!("{}: measure {} row {}: removed hold end without a head on column {}", name, m, r, column));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    unterminated.extend(held.iter().enumerate().filter_map(|(column, head)| head.map(|(m, r)| (m, r, column))));
+    for (m, r, column) in unterminated {
+        measures[m][r][column] = '1';
+        changes.push(format!("{}: measure {} row {}: converted hold without an end on column {} to a tap", name, m, r, column));
+    }
+
+    let mut quantized: Vec<Measure> = Vec::new();
+    for (m, measure) in measures.iter().enumerate() {
+        let q = quantize(measure, columns, changes, &format!("{}: measure {}", name, m));
+        if q.len() != measure.len() {
+            changes.push(format!("{}: measure {}: {} rows -> {} rows", name, m, measure.len(), q.len()));
+        }
+        quantized.push(q);
+    }
+    let trailing = quantized.iter().rev().take_while(|m| m.iter().all(|r| is_empty_row(r))).count().min(quantized.len() - 1);
+    if trailing > 0 {
+        quantized.truncate(quantized.len() - trailing);
+        changes.push(format!("{}: removed {} trailing empty measure(s)", name, trailing));
+    }
+    Some(
+        quantized
+            .iter()
+            .map(|m| m.iter().map(|r| r.iter().collect::<String>()).collect::<Vec<String>>().join("\n"))
+            .collect::<Vec<String>>()
+            .join("\n,\n"),
+    )
+}
+
+fn fix_tag(tag: &mut Tag, prefix: &str, changes: &mut Vec<String>) {
+    let fixed = match tag.key.as_str() {
+        "BPMS" => dedupe_bpms(&tag.value),
+        "DIFFICULTY" => normalize_difficulty(&tag.value).map_or(tag.value.clone(), |d| d.to_string()),
+        "STEPSTYPE" => tag.value.to_lowercase(),
+        _ => return,
+    };
+    if fixed != tag.value {
+        changes.push(format!("{}#{}: \"{}\" -> \"{}\"", prefix, tag.key, tag.value, fixed));
+        tag.value = fixed;
+    }
+}
+
+// 直した内容を返す。空なら変更なし
+pub fn fix_simfile(simfile: &mut Simfile) -> Vec<String> {
+    let mut changes = Vec::new();
+    for tag in simfile.header.iter_mut() {
+        fix_tag(tag, "", &mut changes);
+    }
+    for chart in simfile.charts.iter_mut() {
+        let name = format!("{} {}", chart.get("STEPSTYPE").unwrap_or(""), chart.get("DIFFICULTY").unwrap_or(""));
+        for tag in chart.tags.iter_mut() {
+            if tag.key != "NOTES" {
+                fix_tag(tag, &format!("{}: ", name), &mut changes);
+            }
+        }
+        if let Some(tag) = chart.tags.iter_mut().find(|t| t.key == "NOTES") {
+            let mut notes_changes = Vec::new();
+            if let Some(notes) = fix_notes(&tag.value, &name, &mut notes_changes) {
+                // 行数が変わらなくても書式(空白や改行)だけ違う場合は変更として数えない
+                if !notes_changes.is_empty() {
+                    tag.value = notes;
+                }
+            }
+            changes.extend(notes_changes);
+        }
+    }
+    changes
+}
+
+#[test]
+fn test_fix_simfile() {
+    use crate::simfile::Format;
+    let sm = "#BPMS:0=120,0=150,4=150,8=180;\n\
+        #NOTES:dance-single::hard:5::\n\
+        3000\n0000\n0000\n0000\n0000\n0000\n0000\n0000\n0200\n0000\n0000\n0000\n0000\n0000\n0000\n0000\n,\n\
+        0000\n0000\n0000\n0000\n,\n0000\n0000\n0000\n0000\n;";
+    let mut simfile = Simfile::parse(sm, Format::Sm);
+    let changes = fix_simfile(&mut simfile);
+    assert_eq!(changes.len(), 6);
+    assert_eq!(simfile.get("BPMS"), Some("0=150,8=180"));
+    assert_eq!(simfile.charts[0].get("DIFFICULTY"), Some("Hard"));
+    assert_eq!(simfile.charts[0].get("NOTES"), Some("0000\n0000\n0100\n0000"));
+    // 直した後は何も変わらない
+    assert!(fix_simfile(&mut simfile).is_empty());
+
+    // freeze中の列で始まるfreezeがあれば、先のfreezeをタップにする
+    let mut changes = Vec::new();
+    let notes = fix_notes("2000\n0000\n2000\n3000", "test", &mut changes).unwrap();
+    assert_eq!(notes, "1000\n0000\n2000\n3000");
+    assert_eq!(changes.len(), 1);
+}
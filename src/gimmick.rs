@@ -2,6 +2,11 @@ use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 const NOTE_UNIT: i32 = 192;
 
+// "拍=値" の拍の部分
+fn parse_beat(s: Option<&str>) -> Result<f32, String> {
+    s.ok_or("no beat")?.trim().parse::<f32>().map_err(|e| e.to_string())
+}
+
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct Stop {
     pub offset: i32,
@@ -11,8 +16,8 @@ impl FromStr for Stop {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut s = s.split('=');
-        let offset = (s.next().unwrap().parse::<f32>().unwrap() * (NOTE_UNIT / 4) as f32) as i32;
-        let time = s.next().unwrap().parse::<f32>().unwrap();
+        let offset = (parse_beat(s.next())? * (NOTE_UNIT / 4) as f32) as i32;
+        let time = s.next().ok_or("no stop length")?.trim().parse::<f32>().map_err(|e| e.to_string())?;
         Ok(Stop { offset, time })
     }
 }
@@ -27,12 +32,83 @@ impl FromStr for Bpm {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut s = s.split('=');
-        let offset = (s.next().unwrap().parse::<f32>().unwrap() * (NOTE_UNIT / 4) as f32) as i32;
-        let bpm = s.next().unwrap().parse::<f32>().unwrap();
+        let offset = (parse_beat(s.next())? * (NOTE_UNIT / 4) as f32) as i32;
+        let bpm = s.next().ok_or("no bpm")?.trim().parse::<f32>().map_err(|e| e.to_string())?;
         Ok(Bpm { offset, bpm })
     }
 }
 
+// 拍子。offsetの位置から numerator/denominator 拍子になる
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TimeSignature {
+    pub offset: i32,
+    pub numerator: i32,
+    pub denominator: i32,
+}
+impl FromStr for TimeSignature {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut s = s.split('=');
+        let offset = (parse_beat(s.next())? * (NOTE_UNIT / 4) as f32) as i32;
+        let numerator = s.next().ok_or("no numerator")?.trim().parse::<i32>().map_err(|e| e.to_string())?;
+        let denominator = s.next().ok_or("no denominator")?.trim().parse::<i32>().map_err(|e| e.to_string())?;
+        if numerator <= 0 || denominator <= 0 {
+            return Err(format!("{}/{} is not a valid time signature", numerator, denominator));
+        }
+        Ok(TimeSignature { offset, numerator, denominator })
+    }
+}
+impl TimeSignature {
+    // 1小節の長さ(offset単位)
+    pub fn measure_length(&self) -> i32 {
+        NOTE_UNIT * self.numerator / self.denominator
+    }
+}
+
+// 各小節の先頭のoffset。endを含む小節まで返す
+// 拍子の指定が無い区間は4/4とする
+pub fn measure_starts(time_signatures: &[TimeSignature], end: i32) -> Vec<i32> {
+    let four_four = TimeSignature { offset: 0, numerator: 4, denominator: 4 };
+    let mut signatures: Vec<TimeSignature> = time_signatures.to_vec();
+    if signatures.first().is_none_or(|t| t.offset > 0) {
+        signatures.insert(0, four_four);
+    }
+    let mut starts = Vec::new();
+    for (i, signature) in signatures.iter().enumerate() {
+        let next = signatures.get(i + 1).map_or(i32::MAX, |t| t.offset);
+        let mut offset = signature.offset;
+        while offset < next && offset <= end {
+            starts.push(offset);
+            offset += signature.measure_length().max(1);
+        }
+        if offset > end {
+            break;
+        }
+    }
+    starts
+}
+
+// 各小節の(先頭, 次の小節の先頭)
+pub fn measure_ranges(time_signatures: &[TimeSignature], end: i32) -> Vec<(i32, i32)> {
+    let starts = measure_starts(time_signatures, end);
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| {
+            let next = starts.get(i + 1).copied().unwrap_or_else(|| {
+                let signature = time_signatures.iter().rev().find(|t| t.offset <= *start);
+                start + signature.map_or(NOTE_UNIT, |t| t.measure_length())
+            });
+            (*start, next)
+        })
+        .collect()
+}
+
+// offsetが何小節目(0始まり)か
+pub fn measure_of(measure_starts: &[i32], offset: i32) -> i32 {
+    measure_starts.partition_point(|&s| s <= offset) as i32 - 1
+}
+
 // TODO: viewer側でdivisionではなくoffsetを取るようにする
 #[derive(Debug, Deserialize, Serialize)]
 pub struct BpmDisplay {
@@ -64,8 +140,38 @@ impl StopDisplay {
 
 
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TimeSignatureDisplay {
+    pub division: f32,
+    pub numerator: i32,
+    pub denominator: i32,
+}
+impl TimeSignatureDisplay {
+    pub fn from_time_signature(time_signature: TimeSignature) -> Self {
+        TimeSignatureDisplay {
+            division: (time_signature.offset as f32) / NOTE_UNIT as f32,
+            numerator: time_signature.numerator,
+            denominator: time_signature.denominator,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Gimmick {
     pub soflan: Vec<BpmDisplay>,
     pub stop: Vec<StopDisplay>,
+    pub time_signature: Vec<TimeSignatureDisplay>,
+}
+
+#[test]
+fn test_measure_starts() {
+    // 4/4が2小節、3/4が2小節、7/8が1小節
+    let signatures: Vec<TimeSignature> = ["0=4=4", "8=3=4", "14=7=8"]
+        .iter()
+        .map(|s| TimeSignature::from_str(s).unwrap())
+        .collect();
+    let starts = measure_starts(&signatures, 800);
+    assert_eq!(starts, vec![0, 192, 384, 528, 672]);
+    assert_eq!(measure_of(&starts, 527), 2);
+    assert_eq!(measure_of(&starts, 700), 4);
 }
\ No newline at end of file
@@ -5,8 +5,12 @@ use crate::arrow::{Division, Color};
 use itertools::Itertools;
 use crate::gimmick::{Bpm, Stop};
 use crate::chart::offset_to_time;
+use std::str::FromStr;
 
-// TODO: 曲の長さの定義を決める
+// 時代ごとの計算式の違いはRadarModelで切り替える
+// 出典: 曲線の式はこのリポジトリの最初の実装から引き継いだもので、元になった資料は記録されていない
+// 今はXだけ。ほかの時代(SuperNOVAの頭打ちやA以降の曲の長さなど)やダブル譜面の数え方は、
+// 公式の値で確かめてから曲名と出典と一緒に足す
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct GrooveRadar {
@@ -17,13 +21,136 @@ pub struct GrooveRadar {
     pub chaos: i32,
 }
 
-// freezeを考慮しないといけない気がする
-fn get_music_length(notes: &[Division]) -> f32 {
-    //notes.last().unwrap().time - notes.first().unwrap().time + 9.0
-    let last_note = notes.last().unwrap();
-    let end = last_note.arrows.iter().filter(|a| a.is_freeze()).map(|a| a.end_time).fold(last_note.time, |m, v| m.max(v));
-    //end - notes.first().unwrap().time + 9.0
-    end + 1.6
+// 切り捨て前の値と、その計算に使った値
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct GrooveRadarDetail {
+    pub stream: f32,
+    pub voltage: f32,
+    pub air: f32,
+    pub freeze: f32,
+    pub chaos: f32,
+    pub measurements: RadarMeasurements,
+}
+
+impl GrooveRadarDetail {
+    pub fn truncate(&self) -> GrooveRadar {
+        GrooveRadar {
+            stream: self.stream as i32,
+            voltage: self.voltage as i32,
+            air: self.air as i32,
+            freeze: self.freeze as i32,
+            chaos: self.chaos as i32,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct RadarMeasurements {
+    // 秒
+    pub music_length: f32,
+    pub beat_count: f32,
+    // stream
+    pub notes_per_minute: f32,
+    // voltage: BPM区間ごとの1小節(4拍)あたりのノーツ数の最大
+    pub max_density: i32,
+    pub average_bpm: f32,
+    pub max_density_per_minute: f32,
+    // air: ジャンプ(と衝撃矢印)の数
+    pub air_count: i32,
+    pub jumps_per_minute: f32,
+    // freeze: freezeの長さの合計(拍)
+    pub freeze_beats: i32,
+    pub freeze_ratio: f32,
+    // chaos
+    pub chaos_base_value: f32,
+    pub bpm_change_per_minute: f32,
+    pub chaos_degree: f32,
+}
+
+pub trait RadarModel {
+    // 曲の長さ(秒)。endは曲の終わり(0拍目からの秒)
+    fn music_length(&self, notes: &[Division], end: f32) -> f32;
+    // voltageとfreezeで使う拍数
+    fn beat_count(&self, notes: &[Division], bpms: &[Bpm], stops: &[Stop]) -> f32;
+    fn groove_radar_detail(&self, notes: &[Division], bpms: &[Bpm], stops: &[Stop], length: &MusicLength) -> GrooveRadarDetail {
+        let measurements = measure(self, notes, bpms, stops, length);
+        GrooveRadarDetail {
+            stream: stream_score(measurements.notes_per_minute),
+            voltage: voltage_score(measurements.max_density_per_minute),
+            air: air_score(measurements.jumps_per_minute),
+            freeze: freeze_score(measurements.freeze_ratio),
+            chaos: chaos_score(measurements.chaos_degree),
+            measurements,
+        }
+    }
+    fn groove_radar(&self, notes: &[Division], bpms: &[Bpm], stops: &[Stop], length: &MusicLength) -> GrooveRadar {
+        self.groove_radar_detail(notes, bpms, stops, length).truncate()
+    }
+}
+
+// X〜2014
+// 曲の長さは0秒から曲の終わりまで、拍数は停止中も数える
+pub struct X;
+
+impl RadarModel for X {
+    fn music_length(&self, _notes: &[Division], end: f32) -> f32 {
+        end
+    }
+    fn beat_count(&self, notes: &[Division], bpms: &[Bpm], stops: &[Stop]) -> f32 {
+        calc_beat_count(notes, bpms, stops)
+    }
+}
+
+// CLIやライブラリから選ぶ時代
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RadarEra {
+    X,
+}
+
+impl FromStr for RadarEra {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x" => Ok(RadarEra::X),
+            _ => Err(format!("{} is not groove radar model", s)),
+        }
+    }
+}
+
+pub fn radar_model(era: RadarEra) -> Box<dyn RadarModel> {
+    match era {
+        RadarEra::X => Box::new(X),
+    }
+}
+
+pub const DEFAULT_TAIL: f32 = 1.6;
+
+// 曲の終わりの決め方
+#[derive(Copy, Clone, Debug)]
+pub struct MusicLength {
+    // 曲の終わり(0拍目からの秒)。LASTSECONDHINTや音声ファイルの長さから分かる場合
+    pub end: Option<f32>,
+    // endが無い場合に、最後のノーツ(freezeの終点)とその後の停止に足す秒数
+    pub tail: f32,
+}
+
+impl Default for MusicLength {
+    fn default() -> Self {
+        MusicLength { end: None, tail: DEFAULT_TAIL }
+    }
+}
+
+pub fn get_song_end(notes: &[Division], bpms: &[Bpm], stops: &[Stop], length: &MusicLength) -> f32 {
+    length.end.unwrap_or_else(|| {
+        let last = notes
+            .iter()
+            .flat_map(|d| std::iter::once(d.offset).chain(d.arrows.iter().filter(|a| a.is_hold()).map(|a| a.end)))
+            .max()
+            .unwrap_or(0);
+        // offset_to_timeは同じ位置の停止を含まないので、最後のノーツ以降の停止を足す
+        let stop_after: f32 = stops.iter().filter(|s| s.offset >= last).map(|s| s.time).sum();
+        offset_to_time(last, bpms, stops) + stop_after + length.tail
+    })
 }
 
 fn count_subsequent_notes(notes: &[Division], offset: i32) -> i32 {
@@ -45,7 +172,7 @@ fn count_subsequent_notes(notes: &[Division], offset: i32) -> i32 {
 //   -> [1,4,10,12], [21,30], [], [35]
 fn create_bpm_section_list(notes: &[Division], bpms: &[Bpm]) -> Vec<Vec<Division>> {
     let mut partitions: Vec<i32> = bpms.iter().map(|bpm| bpm.offset).collect();
-    partitions.push(notes.last().unwrap().offset);
+    partitions.push(notes.last().map_or(0, |d| d.offset));
     let ranges = partitions.windows(2).map(|pair| (pair[0], pair[1]));
     ranges.map(|range| 
         notes.iter().filter(|div| div.offset >= range.0 && div.offset < range.1).cloned().collect()
@@ -62,23 +189,14 @@ fn calc_max_notes_in_bpm_section(section: &[Division]) -> i32 {
 
 fn calc_max_note_density(notes: &[Division], bpms: &[Bpm]) -> i32 {
     let bpm_section_list = create_bpm_section_list(notes, bpms);
-    bpm_section_list.iter().map(|s| calc_max_notes_in_bpm_section(s)).max().unwrap()
-}
-
-fn calc_stream(notes: &[Division]) -> i32 {
-    let notes_per_min = (notes.len() as f32 / get_music_length(notes)) * 60.0;
-    if notes_per_min < 300.0 {
-        (notes_per_min / 3.0) as i32
-    } else {
-        ((notes_per_min - 139.0) * 100.0 / 161.0) as i32
-    }
+    bpm_section_list.iter().map(|s| calc_max_notes_in_bpm_section(s)).max().unwrap_or(0)
 }
 
 fn calc_beat_count(notes: &[Division], bpms: &[Bpm], stops: &[Stop]) -> f32 {
-    let end = Bpm {offset: notes.last().unwrap().offset, bpm:0.0};
+    let end = Bpm {offset: notes.last().map_or(0, |d| d.offset), bpm:0.0};
     let bpms_with_end = bpms.iter().chain(std::iter::once(&end));
     let mut num_beats = 0.0;
-    // 停止の扱いが不明
+    // 停止中も拍数に数える
     for (current_bpm, next_bpm) in bpms_with_end.tuple_windows() {
         let start = offset_to_time(current_bpm.offset, bpms, stops);
         let end = offset_to_time(next_bpm.offset, bpms, stops);
@@ -87,43 +205,80 @@ fn calc_beat_count(notes: &[Division], bpms: &[Bpm], stops: &[Stop]) -> f32 {
     num_beats / 60.0
 }
 
-fn calc_average_bpm(notes: &[Division], bpms: &[Bpm], stops: &[Stop]) -> f32 {
-    calc_beat_count(notes, bpms, stops) * 60.0 / get_music_length(notes)
+// 各値の曲線
+fn stream_score(notes_per_minute: f32) -> f32 {
+    if notes_per_minute < 300.0 {
+        notes_per_minute / 3.0
+    } else {
+        (notes_per_minute - 139.0) * 100.0 / 161.0
+    }
+}
+
+fn voltage_score(max_density_per_minute: f32) -> f32 {
+    if max_density_per_minute < 600.0 {
+        max_density_per_minute / 6.0
+    } else {
+        (max_density_per_minute + 594.0) * 100.0 / 1194.0
+    }
 }
 
-fn calc_voltage(notes: &[Division], bpms: &[Bpm], stops: &[Stop]) -> i32{
-    let max_density = calc_max_note_density(notes, bpms);
-    let average_bpm = calc_average_bpm(notes, bpms, stops);
-    let max_density_per_min = (max_density as f32) * average_bpm / 4.0;
-    if max_density_per_min < 600.0 {
-        (max_density_per_min / 6.0) as i32
+fn air_score(jumps_per_minute: f32) -> f32 {
+    if jumps_per_minute < 55.0 {
+        jumps_per_minute * 20.0 / 11.0
     } else {
-        ((max_density_per_min + 594.0) * 100.0 / 1194.0) as i32
+        (jumps_per_minute + 36.0) * 100.0 / 91.0
     }
 }
 
-fn calc_air(notes: &[Division]) -> i32{
-    let jumps = notes.iter().filter(|d| d.is_jump()).count();
-    let shocks = notes.iter().filter(|d| d.is_shock()).count();
-    let jump_per_min = ((jumps + shocks) * 60) as f32 / get_music_length(notes);
-    if jump_per_min < 55.0 {
-        (jump_per_min * 20.0 / 11.0) as i32
+fn freeze_score(freeze_ratio: f32) -> f32 {
+    if freeze_ratio < 3500.0 {
+        freeze_ratio / 35.0
     } else {
-        ((jump_per_min + 36.0) * 100.0 / 91.0) as i32
+        (freeze_ratio + 2484.0) * 100.0 / 5984.0
     }
 }
 
-fn calc_freeze(notes: &[Division], bpms: &[Bpm], stops: &[Stop]) -> i32{
-    let total_len: i32 = notes.iter().map(|d| {
+fn chaos_score(chaos_degree: f32) -> f32 {
+    if chaos_degree < 2000.0 {
+        chaos_degree / 20.0
+    } else {
+        (chaos_degree + 21605.0) * 100.0 / 23605.0
+    }
+}
+
+fn calc_freeze_beats(notes: &[Division]) -> i32 {
+    notes.iter().map(|d| {
         // 良い書き方がありそう
         let len = d.arrows.iter().filter(|a| a.is_freeze()).map(|a| a.end - d.offset).max();
         len.unwrap_or(0)
-    }).sum::<i32>() / (NOTE_UNIT/4);
-    let freeze_ratio = (10000 * total_len) as f32 / calc_beat_count(notes, bpms, stops);
-    if freeze_ratio < 3500.0 {
-        (freeze_ratio / 35.0) as i32
-    } else {
-        ((freeze_ratio + 2484.0) * 100.0 / 5984.0) as i32
+    }).sum::<i32>() / (NOTE_UNIT/4)
+}
+
+fn measure<M: RadarModel + ?Sized>(model: &M, notes: &[Division], bpms: &[Bpm], stops: &[Stop], length: &MusicLength) -> RadarMeasurements {
+    let music_length = model.music_length(notes, get_song_end(notes, bpms, stops, length));
+    let beat_count = model.beat_count(notes, bpms, stops);
+    let max_density = calc_max_note_density(notes, bpms);
+    let average_bpm = beat_count * 60.0 / music_length;
+    // ジャンプと衝撃矢印の行
+    let air_count = notes.iter().filter(|d| d.is_jump() || d.is_shock()).count();
+    let freeze_beats = calc_freeze_beats(notes);
+    let chaos_base_value = calc_chaos_base_value(notes);
+    let bpm_change_per_minute = calc_total_bpm_change(bpms, stops) * 60.0 / music_length;
+    let change_correction = 1.0 + (bpm_change_per_minute / 1500.0);
+    RadarMeasurements {
+        music_length,
+        beat_count,
+        notes_per_minute: (notes.len() as f32 / music_length) * 60.0,
+        max_density,
+        average_bpm,
+        max_density_per_minute: (max_density as f32) * average_bpm / 4.0,
+        air_count: air_count as i32,
+        jumps_per_minute: (air_count * 60) as f32 / music_length,
+        freeze_beats,
+        freeze_ratio: (10000 * freeze_beats) as f32 / beat_count,
+        chaos_base_value,
+        bpm_change_per_minute,
+        chaos_degree: chaos_base_value * change_correction * 100.0 / music_length,
     }
 }
 
@@ -167,8 +322,8 @@ fn calc_total_bpm_change(bpms: &[Bpm], stops: &[Stop]) -> f32 {
     for stop in stops {
         gimmicks.push(BpmOrStop {offset: stop.offset, value: stop.time, kind: Kind::Stop});
     }
-    // TODO: stopとbpmが同じタイミングで起きる場合はstopのみ考慮する
-    gimmicks.sort_by(|a,b| a.offset.cmp(&b.offset));
+    // TODO: stopとbpmが同じタイミングで起きる場合はstopのみ考慮する(公式の値で確かめてから)
+    gimmicks.sort_by_key(|a| a.offset);
     let mut total_bpm_change = 0.0;
     let mut current_bpm = bpms[0].bpm;
     for gimmick in gimmicks {
@@ -185,27 +340,36 @@ fn calc_total_bpm_change(bpms: &[Bpm], stops: &[Stop]) -> f32 {
     total_bpm_change
 }
 
-fn calc_chaos(notes: &[Division], bpms: &[Bpm], stops: &[Stop]) -> i32{
-    let music_length = get_music_length(notes);
-    let base_value = calc_chaos_base_value(notes);
-    let change_per_min = calc_total_bpm_change(bpms, stops) * 60.0 / music_length;
-    let change_correction = 1.0 + (change_per_min / 1500.0);
-    let chaos_degree = base_value * change_correction * 100.0 / music_length;
-    if chaos_degree < 2000.0 {
-        (chaos_degree / 20.0) as i32
-    } else {
-        ((chaos_degree + 21605.0) * 100.0 / 23605.0) as i32
-    }
+pub fn get_groove_radar(notes: &[Division], bpms:&[Bpm], stops: &[Stop], length: &MusicLength, era: RadarEra) -> GrooveRadar {
+    radar_model(era).groove_radar(notes, bpms, stops, length)
+}
 
- 
+pub fn get_groove_radar_detail(notes: &[Division], bpms:&[Bpm], stops: &[Stop], length: &MusicLength, era: RadarEra) -> GrooveRadarDetail {
+    radar_model(era).groove_radar_detail(notes, bpms, stops, length)
 }
 
-pub fn get_groove_radar(notes: &[Division], bpms:&[Bpm], stops: &[Stop]) -> GrooveRadar {
-    GrooveRadar {
-        stream: calc_stream(notes),
-        voltage: calc_voltage(notes, bpms, stops),
-        air: calc_air(notes),
-        freeze: calc_freeze(notes, bpms, stops),
-        chaos: calc_chaos(notes, bpms, stops),
+// 合成の譜面にこの実装が出した値を書いたもので、計算が変わっていないことしか確かめられない
+// 公式の値との比較は testdata/groove_radar/PROVENANCE.md を参照
+#[test]
+fn test_radar_regression() {
+    use crate::chart::create_chart;
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/groove_radar");
+    let expected_charts: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(dir.join("regression.json")).unwrap()).unwrap();
+    for entry in expected_charts.as_array().unwrap() {
+        let path = dir.join(entry["file"].as_str().unwrap()).to_str().unwrap().to_string();
+        let mut chart = create_chart(&path)
+            .into_iter()
+            .find(|c| format!("{:?}", c.info.difficulty) == entry["difficulty"].as_str().unwrap())
+            .unwrap();
+        for (era, expected) in entry["expected"].as_object().unwrap() {
+            chart.update_groove_radar(era.parse().unwrap(), false);
+            let actual = [chart.info.stream, chart.info.voltage, chart.info.air, chart.info.freeze, chart.info.chaos];
+            let error = ["stream", "voltage", "air", "freeze", "chaos"]
+                .iter()
+                .zip(actual)
+                .map(|(key, v)| (expected[*key].as_i64().unwrap() - v as i64).abs() as f64)
+                .sum::<f64>() / 5.0;
+            assert!(error <= entry["tolerance"].as_f64().unwrap(), "{} {} {}: {:?} mean error {:.2}", entry["file"], entry["difficulty"], era, actual, error);
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use crate::arrow::NOTE_UNIT;
+use crate::chart::{Chart, ChartType, Difficulty, make_chart, rows_to_bars, str_to_notes};
+use crate::gimmick::Bpm;
+use crate::simfile::{remove_comments, split_tags};
+
+// KSF(Kick It Up)形式
+// 1ファイル1譜面で、難易度とsingle/doubleはファイル名で区別する
+// STEPは1行13文字(左から1P側5パネル、2P側5パネル)で、1行は1/TICKCOUNT拍
+// "4"が続く区間がfreeze、"2222222222222"で譜面が終わる
+// BUNKIとSTARTTIMEは1/100秒単位
+
+const END_OF_STEPS: &str = "2222222222222";
+
+// ファイル名から(種類, 難易度)を決める
+fn parse_file_name(filepath: &str) -> (ChartType, Difficulty) {
+    let name = Path::new(filepath)
+        .file_stem()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_lowercase();
+    // doubleの譜面は同じ出力ファイル名にならないように、それぞれ別の難易度にする
+    if name.contains("halfdouble") {
+        (ChartType::PumpDouble, Difficulty::Easy)
+    } else if name.contains("nightmare") {
+        (ChartType::PumpDouble, Difficulty::Challenge)
+    } else if name.contains("freestyle") {
+        (ChartType::PumpDouble, Difficulty::Hard)
+    } else if name.contains("double") {
+        (ChartType::PumpDouble, Difficulty::Medium)
+    } else if name.contains("crazy") {
+        (ChartType::PumpSingle, Difficulty::Hard)
+    } else if name.contains("hard") {
+        (ChartType::PumpSingle, Difficulty::Medium)
+    } else if name.contains("easy") || name.contains("normal") {
+        (ChartType::PumpSingle, Difficulty::Easy)
+    } else {
+        (ChartType::PumpSingle, Difficulty::Edit)
+    }
+}
+
+fn default_level(difficulty: Difficulty) -> i32 {
+    match difficulty {
+        Difficulty::Beginner => 1,
+        Difficulty::Easy => 3,
+        Difficulty::Medium => 5,
+        Difficulty::Hard => 7,
+        Difficulty::Challenge => 9,
+        Difficulty::Edit => 0,
+    }
+}
+
+// BUNKI(曲の先頭からの時間)を、その時点までのBPMを使ってoffsetに変換する
+fn bunki_to_bpms(first_bpm: f32, changes: &[(f32, f32)], start_time: f32) -> Vec<Bpm> {
+    let mut bpms = vec![Bpm { offset: 0, bpm: first_bpm }];
+    let mut beat = 0.0;
+    let mut prev_time = start_time;
+    for &(time, bpm) in changes {
+        let current = bpms.last().unwrap().bpm;
+        beat += (time - prev_time) * current / 60.0;
+        prev_time = time;
+        bpms.push(Bpm {
+            offset: (beat * (NOTE_UNIT / 4) as f32).round() as i32,
+            bpm,
+        });
+    }
+    bpms
+}
+
+struct Steps {
+    rows: BTreeMap<i32, String>,
+    // "|B150|" のような行内のBPM変化
+    bpm_changes: Vec<Bpm>,
+}
+
+fn parse_steps(filepath: &str, steps: &str, width: usize, tick_count: i32) -> Steps {
+    let mut rows = BTreeMap::new();
+    let mut bpm_changes = Vec::new();
+    let mut tick_count = tick_count;
+    // TICKCOUNTが48の約数でない場合は丸める
+    let mut offset: f32 = 0.0;
+    let mut holding: Vec<Option<i32>> = vec![None; width];
+    let mut prev_offset = 0;
+    for line in steps.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        if line == END_OF_STEPS {
+            break;
+        }
+        if line.starts_with('|') {
+            let value = line.trim_matches('|');
+            match value.chars().next() {
+                Some('T') => tick_count = value[1..].parse().unwrap(),
+                Some('B') => bpm_changes.push(Bpm {
+                    offset: offset.round() as i32,
+                    bpm: value[1..].parse().unwrap(),
+                }),
+                _ => eprintln!("WARNING: {}: unsupported KSF command {}", filepath, line),
+            }
+            continue;
+        }
+        let current = offset.round() as i32;
+        let mut row: Vec<char> = vec!['0'; width];
+        for (col, c) in line.chars().take(width).enumerate() {
+            match (c, holding[col]) {
+                ('4', None) => {
+                    row[col] = '2';
+                    holding[col] = Some(current);
+                }
+                ('4', Some(_)) => {}
+                (c, Some(start)) => {
+                    end_hold(&mut rows, col, start, prev_offset, width);
+                    holding[col] = None;
+                    if c == '1' {
+                        row[col] = '1';
+                    }
+                }
+                ('1', None) => row[col] = '1',
+                _ => {}
+            }
+        }
+        if row.iter().any(|&c| c != '0') {
+            merge_row(&mut rows, current, &row);
+        }
+        prev_offset = current;
+        offset += (NOTE_UNIT / 4) as f32 / tick_count as f32;
+    }
+    for (col, start) in holding.iter().enumerate() {
+        if let Some(start) = start {
+            end_hold(&mut rows, col, *start, prev_offset, width);
+        }
+    }
+    Steps { rows, bpm_changes }
+}
+
+// 最後の"4"の行をfreezeの終点にする。1行だけのfreezeは通常ノーツとして扱う
+fn end_hold(rows: &mut BTreeMap<i32, String>, col: usize, start: i32, last: i32, width: usize) {
+    if start == last {
+        let mut start_row: Vec<char> = rows.get(&start).unwrap().chars().collect();
+        start_row[col] = '1';
+        rows.insert(start, start_row.iter().collect());
+    } else {
+        let mut end_row = vec!['0'; width];
+        end_row[col] = '3';
+        merge_row(rows, last, &end_row);
+    }
+}
+
+fn merge_row(rows: &mut BTreeMap<i32, String>, offset: i32, row: &[char]) {
+    let merged: String = match rows.get(&offset) {
+        Some(existing) => existing
+            .chars()
+            .zip(row.iter())
+            .map(|(a, &b)| if b != '0' { b } else { a })
+            .collect(),
+        None => row.iter().collect(),
+    };
+    rows.insert(offset, merged);
+}
+
+pub fn ksf_to_chart(filepath: &String) -> Vec<Chart> {
+    let contents = fs::read_to_string(filepath).expect("file open error");
+    let tags = split_tags(&remove_comments(&contents));
+    let get = |key: &str| tags.iter().find(|t| t.key == key).map(|t| t.value.as_str());
+    let (chart_type, difficulty) = parse_file_name(filepath);
+    let width = chart_type.directions().len();
+
+    let start_time = get("STARTTIME").map_or(0.0, |s| s.parse::<f32>().unwrap() / 100.0);
+    let mut changes = Vec::new();
+    for (bpm_key, bunki_key) in [("BPM2", "BUNKI"), ("BPM3", "BUNKI2")] {
+        if let (Some(bpm), Some(bunki)) = (get(bpm_key), get(bunki_key)) {
+            changes.push((bunki.parse::<f32>().unwrap() / 100.0, bpm.parse::<f32>().unwrap()));
+        }
+    }
+    let first_bpm = get("BPM").unwrap().parse().unwrap();
+    let mut bpms = bunki_to_bpms(first_bpm, &changes, start_time);
+
+    let tick_count = get("TICKCOUNT").map_or(4, |s| s.parse().unwrap());
+    let steps = parse_steps(filepath, get("STEP").unwrap_or(""), width, tick_count);
+    bpms.extend(steps.bpm_changes);
+    bpms.sort_by_key(|b| b.offset);
+
+    let level = get("DIFFICULTY").map_or(default_level(difficulty), |s| s.parse().unwrap());
+    let bars = rows_to_bars(&steps.rows, width);
+    let notes = str_to_notes(bars.iter().map(|s| s.as_str()).collect(), chart_type, &bpms, &[], &[]);
+    vec![make_chart(chart_type, difficulty, level, notes, &bpms, &[], &[])]
+}
+
+// ディレクトリ内の "song.mp3" や "title.png" のようなファイルを探す
+fn find_file_by_stem(dir: &Path, stem: &str) -> Option<String> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().into_string().unwrap())
+        .filter(|name| !name.to_lowercase().ends_with(".ksf"))
+        .find(|name| Path::new(name).file_stem().unwrap().to_str().unwrap().to_lowercase() == stem)
+}
+
+// 曲情報を.smと同じキー(TITLE, MUSIC, BANNER, OFFSET)で返す
+pub fn ksf_props(filepath: &str) -> HashMap<String, String> {
+    let contents = fs::read_to_string(filepath).expect("file open error");
+    let mut props: HashMap<String, String> = split_tags(&remove_comments(&contents))
+        .into_iter()
+        .filter(|t| t.key != "STEP")
+        .map(|t| (t.key, t.value))
+        .collect();
+    let dir = Path::new(filepath).parent().unwrap();
+    if let Some(music) = find_file_by_stem(dir, "song") {
+        props.insert("MUSIC".to_string(), music);
+    }
+    if let Some(banner) = find_file_by_stem(dir, "title") {
+        props.insert("BANNER".to_string(), banner);
+    }
+    if let Some(start_time) = props.get("STARTTIME") {
+        let offset = -start_time.parse::<f32>().unwrap() / 100.0;
+        props.insert("OFFSET".to_string(), offset.to_string());
+    }
+    props
+}
+
+#[test]
+fn test_parse_steps() {
+    let steps = "|T2|\n1000000000000\n0400000000000\n|T4|\n0400000000000\n0410000000000\n|B200|\n0000100000000\n2222222222222\n1000000000000\n";
+    let steps = parse_steps("test.ksf", steps, 5, 4);
+    // 2行は8分、それ以降は4行で1拍
+    assert_eq!(steps.rows.get(&0), Some(&"10000".to_string()));
+    assert_eq!(steps.rows.get(&24), Some(&"02000".to_string()));
+    assert_eq!(steps.rows.get(&60), Some(&"03100".to_string()));
+    assert_eq!(steps.rows.get(&72), Some(&"00001".to_string()));
+    assert_eq!(steps.rows.len(), 4);
+    assert_eq!(steps.bpm_changes[0].offset, 72);
+}
+
+#[test]
+fn test_parse_file_name() {
+    assert_eq!(parse_file_name("songs/x/Crazy_1.ksf"), (ChartType::PumpSingle, Difficulty::Hard));
+    let doubles: Vec<Difficulty> = ["Double.ksf", "Freestyle.ksf", "Nightmare.ksf", "HalfDouble.ksf"].iter().map(|f| parse_file_name(f).1).collect();
+    assert_eq!(doubles, vec![Difficulty::Medium, Difficulty::Hard, Difficulty::Challenge, Difficulty::Easy]);
+}
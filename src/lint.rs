@@ -0,0 +1,242 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::arrow::NOTE_UNIT;
+use crate::chart::ChartType;
+use crate::gimmick::{Bpm, Stop};
+use crate::simfile::{Simfile, SimfileChart};
+
+// .sm/.sscを変換せずに検査する
+// 位置は小節(measure)と小節内の行(row)で、どちらも0始まり。タイミングの問題は行の代わりに拍(beat)で示す
+
+// METERとして妥当な範囲
+const METER_RANGE: (i32, i32) = (1, 30);
+const UNITS_PER_BEAT: f32 = (NOTE_UNIT / 4) as f32;
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct LintIssue {
+    pub file: String,
+    // "dance-single Hard" など。ヘッダの問題ならNone
+    pub chart: Option<String>,
+    pub measure: Option<usize>,
+    pub row: Option<usize>,
+    pub beat: Option<f32>,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl LintIssue {
+    pub fn location(&self) -> String {
+        let mut location = self.file.clone();
+        if let Some(chart) = &self.chart {
+            location += &format!(" [{}]", chart);
+        }
+        if let Some(measure) = self.measure {
+            location += &format!(" measure {}", measure);
+        }
+        if let Some(row) = self.row {
+            location += &format!(" row {}", row);
+        }
+        if let Some(beat) = self.beat {
+            location += &format!(" beat {}", beat);
+        }
+        location
+    }
+}
+
+// .smではWARPSの代わりに負のBPMや負の停止でワープを書く。(開始の拍, 長さ(拍))にする
+// 負のBPMの区間で戻った時間を次のBPMで進み直すところまでがワープ(次のBPMは変わらないものとする)
+fn negative_timing_warps(bpms: &[Bpm], stops: &[Stop]) -> Vec<(f32, f32)> {
+    let beat = |offset: i32| offset as f32 / UNITS_PER_BEAT;
+    let bpm_at = |offset: i32| bpms.iter().rev().find(|b| b.offset <= offset).map_or(0.0, |b| b.bpm);
+    let mut warps: Vec<(f32, f32)> = bpms
+        .windows(2)
+        .filter(|w| w[0].bpm < 0.0 && w[1].bpm > 0.0)
+        .map(|w| {
+            let length = beat(w[1].offset) - beat(w[0].offset);
+            (beat(w[0].offset), length * (1.0 + w[1].bpm / -w[0].bpm))
+        })
+        .collect();
+    warps.extend(stops.iter().filter(|s| s.time < 0.0).map(|s| (beat(s.offset), -s.time * bpm_at(s.offset).abs() / 60.0)));
+    warps
+}
+
+struct Linter<'a> {
+    file: &'a str,
+    chart: Option<String>,
+    issues: Vec<LintIssue>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, measure: Option<usize>, row: Option<usize>, beat: Option<f32>, code: &'static str, message: String) {
+        self.issues.push(LintIssue { file: self.file.to_string(), chart: self.chart.clone(), measure, row, beat, code, message });
+    }
+
+    fn report_at_offset(&mut self, offset: i32, code: &'static str, message: String) {
+        let measure = (offset / NOTE_UNIT).max(0) as usize;
+        self.report(Some(measure), None, Some(offset as f32 / UNITS_PER_BEAT), code, message);
+    }
+
+    // "拍=値,..." の列を読む。読めない項目は報告して飛ばす
+    fn parse_list<T: FromStr<Err = String>>(&mut self, key: &str, value: Option<&str>) -> Vec<T> {
+        let mut items = Vec::new();
+        for item in value.unwrap_or("").split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            match item.parse() {
+                Ok(v) => items.push(v),
+                Err(e) => self.report(None, None, None, "invalid-timing", format!("#{}: cannot parse \"{}\": {}", key, item, e)),
+            }
+        }
+        items
+    }
+
+    // ノーツ以外のタイミングの問題
+    fn lint_timing(&mut self, bpms: &[Bpm], stops: &[Stop]) {
+        for bpm in bpms.iter().filter(|b| b.bpm <= 0.0) {
+            self.report_at_offset(bpm.offset, "non-positive-bpm", format!("BPM {} is not positive", bpm.bpm));
+        }
+        for stop in stops {
+            if bpms.iter().skip(1).any(|b| b.offset == stop.offset) {
+                self.report_at_offset(stop.offset, "stop-on-bpm-change", "stop is on the same row as a BPM change".to_string());
+            }
+        }
+    }
+
+    fn lint_notes(&mut self, notes: &str, width: Option<usize>, warps: &[(f32, f32)]) {
+        let measures: Vec<Vec<&str>> = notes
+            .split(',')
+            .map(|m| m.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).collect())
+            .collect();
+        // 列ごとに押しっぱなしのfreeze/rollの始点(小節, 行)
+        // 種類の分からない譜面は最初の行の長さを列数とする
+        let columns = width.unwrap_or_else(|| measures.iter().flatten().next().map_or(0, |r| r.chars().count()));
+        let mut held: Vec<Option<(usize, usize)>> = vec![None; columns];
+        for (m, rows) in measures.iter().enumerate() {
+            if rows.is_empty() || !(NOTE_UNIT as usize).is_multiple_of(rows.len()) {
+                self.report(Some(m), None, None, "bad-row-count", format!("measure has {} rows (must divide {})", rows.len(), NOTE_UNIT));
+            }
+            for (r, row) in rows.iter().enumerate() {
+                let beat = m as f32 * 4.0 + r as f32 * 4.0 / rows.len() as f32;
+                if let Some(width) = width {
+                    if row.chars().count() != width {
+                        self.report(Some(m), Some(r), None, "bad-row-width", format!("\"{}\" should have {} columns", row, width));
+                        continue;
+                    }
+                }
+                if row.chars().any(|c| "124L".contains(c)) && warps.iter().any(|(start, length)| *start < beat && beat < start + length) {
+                    self.report(Some(m), Some(r), Some(beat), "note-in-warp", format!("\"{}\" is inside a warp", row));
+                }
+                for (column, c) in row.chars().take(columns).enumerate() {
+                    match c {
+                        '2' | '4' => {
+                            if let Some((hm, hr)) = held[column] {
+                                self.report(Some(m), Some(r), None, "hold-on-held-column", format!("column {} is already held since measure {} row {}", column, hm, hr));
+                            }
+                            held[column] = Some((m, r));
+                        }
+                        '3' if held[column].take().is_none() => {
+                            self.report(Some(m), Some(r), None, "hold-end-without-head", format!("column {} has a hold end without a head", column));
+                        }
+                        'M' if held[column].is_some() => {
+                            self.report(Some(m), Some(r), None, "mine-under-hold", format!("mine on column {} while it is held", column));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        for (column, head) in held.iter().enumerate() {
+            if let Some((m, r)) = head {
+                self.report(Some(*m), Some(*r), None, "unterminated-hold", format!("hold on column {} has no end", column));
+            }
+        }
+        let is_empty = |rows: &Vec<&str>| rows.iter().all(|r| r.chars().all(|c| c == '0'));
+        let trailing = measures.iter().rev().take_while(|m| is_empty(m)).count();
+        if trailing > 0 && trailing < measures.len() {
+            let first = measures.len() - trailing;
+            self.report(Some(first), None, None, "trailing-empty-measures", format!("{} empty measure(s) at the end", trailing));
+        }
+    }
+
+    fn lint_chart(&mut self, simfile: &Simfile, chart: &SimfileChart) {
+        // .sscでは譜面ごとのタイミングがあればそちらを使う
+        let timing = |key: &str| chart.get(key).or_else(|| simfile.get(key));
+        let bpms: Vec<Bpm> = self.parse_list("BPMS", timing("BPMS"));
+        let stops: Vec<Stop> = self.parse_list("STOPS", timing("STOPS"));
+        let mut warps: Vec<(f32, f32)> = timing("WARPS")
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|w| w.split_once('='))
+            .filter_map(|(beat, length)| Some((beat.trim().parse().ok()?, length.trim().parse().ok()?)))
+            .collect();
+        warps.extend(negative_timing_warps(&bpms, &stops));
+        if chart.get("BPMS").is_some() || chart.get("STOPS").is_some() {
+            self.lint_timing(&bpms, &stops);
+        }
+        match chart.get("METER").map(|m| m.trim().parse::<i32>()) {
+            Some(Ok(meter)) if meter < METER_RANGE.0 || meter > METER_RANGE.1 => {
+                self.report(None, None, None, "meter-out-of-range", format!("METER {} is outside {}..{}", meter, METER_RANGE.0, METER_RANGE.1))
+            }
+            Some(Err(_)) => self.report(None, None, None, "meter-out-of-range", format!("METER \"{}\" is not a number", chart.get("METER").unwrap())),
+            _ => {}
+        }
+        let width = chart.get("STEPSTYPE").and_then(|t| ChartType::from_str(t.trim()).ok()).map(|t| t.directions().len());
+        self.lint_notes(chart.get("NOTES").unwrap_or(""), width, &warps);
+    }
+}
+
+pub fn lint_simfile(file: &str, simfile: &Simfile) -> Vec<LintIssue> {
+    let mut linter = Linter { file, chart: None, issues: Vec::new() };
+    let bpms: Vec<Bpm> = linter.parse_list("BPMS", simfile.get("BPMS"));
+    let stops: Vec<Stop> = linter.parse_list("STOPS", simfile.get("STOPS"));
+    linter.lint_timing(&bpms, &stops);
+
+    let mut seen: HashMap<(String, String), usize> = HashMap::new();
+    for chart in &simfile.charts {
+        let steps_type = chart.get("STEPSTYPE").unwrap_or("").trim().to_string();
+        let difficulty = chart.get("DIFFICULTY").unwrap_or("").trim().to_string();
+        linter.chart = Some(format!("{} {}", steps_type, difficulty));
+        // Editは何譜面あってもよい
+        let count = seen.entry((steps_type, difficulty.clone())).or_insert(0);
+        *count += 1;
+        if *count > 1 && !difficulty.eq_ignore_ascii_case("edit") {
+            linter.report(None, None, None, "duplicate-difficulty", format!("{} appears {} times", difficulty, count));
+        }
+        linter.lint_chart(simfile, chart);
+    }
+    linter.issues
+}
+
+pub fn lint_file(file: &str) -> Vec<LintIssue> {
+    lint_simfile(file, &Simfile::load(file))
+}
+
+#[test]
+fn test_lint() {
+    let sm = "#BPMS:0=120,4=-60;#STOPS:4=0.5;\n\
+        #NOTES:dance-single::Hard:5::\n2000\n0000\nM000\n0000\n,\n2000\n0000\n0000\n0000\n0000\n,\n0000\n0000\n0000\n0000\n;\n\
+        #NOTES:dance-single::Hard:99::\n1000\n0000\n0000\n0000\n;";
+    let issues = lint_simfile("a.sm", &Simfile::parse(sm, crate::simfile::Format::Sm));
+    let codes: Vec<(&str, Option<usize>, Option<usize>)> = issues.iter().map(|i| (i.code, i.measure, i.row)).collect();
+    assert_eq!(
+        codes,
+        vec![
+            ("non-positive-bpm", Some(1), None),
+            ("stop-on-bpm-change", Some(1), None),
+            ("mine-under-hold", Some(0), Some(2)),
+            ("bad-row-count", Some(1), None),
+            ("hold-on-held-column", Some(1), Some(0)),
+            ("unterminated-hold", Some(1), Some(0)),
+            ("trailing-empty-measures", Some(2), None),
+            ("duplicate-difficulty", None, None),
+            ("meter-out-of-range", None, None),
+        ]
+    );
+
+    // .smの負のBPMのワープ(4拍目から、-120で1拍戻って120で1拍進み直す6拍目まで)と負の停止のワープ
+    let sm = "#BPMS:0=120,4=-120,5=120;#STOPS:10=-1.0;\n\
+        #NOTES:dance-single::Hard:5::\n0000\n0000\n0000\n0000\n,\n1000\n0100\n0000\n0001\n,\n0000\n0000\n1000\n1000\n;";
+    let issues = lint_simfile("b.sm", &Simfile::parse(sm, crate::simfile::Format::Sm));
+    let warped: Vec<(Option<usize>, Option<usize>)> = issues.iter().filter(|i| i.code == "note-in-warp").map(|i| (i.measure, i.row)).collect();
+    assert_eq!(warped, vec![(Some(1), Some(1)), (Some(2), Some(3))]);
+}
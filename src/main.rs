@@ -11,6 +11,25 @@ pub mod arrow;
 pub mod gimmick;
 pub mod chart;
 pub mod groove_radar;
+pub mod simfile;
+pub mod dwi;
+pub mod ksf;
+pub mod osu;
+pub mod bms;
+pub mod stream;
+pub mod density;
+pub mod step_count;
+pub mod audio;
+pub mod assets;
+pub mod export;
+pub mod lint;
+pub mod fix;
+pub mod diff;
+pub mod chart_hash;
+pub mod parity;
+pub mod pattern;
+pub mod transform;
+pub mod scroll_speed;
 
 // bar: 4分が4つ入る単位
 // division: barを192分割して矢印があるところ
@@ -20,6 +39,9 @@ pub mod groove_radar;
 struct Music {
     path: String,
     offset: f32,
+    // 音声ファイルのヘッダから読んだ長さ(秒)、サンプリング周波数、チャンネル数
+    #[serde(flatten)]
+    audio: Option<audio::AudioInfo>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -30,12 +52,21 @@ struct Song {
     bpm: String,
     music: Music,
     banner: String,
+    background: String,
+    jacket: String,
+    // --export-assetsで作ったもの
+    #[serde(skip_serializing_if = "Option::is_none")]
+    banner_thumbnail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preview: Option<String>,
     timestamp: String,
 }
 
-// TODO: chartは外部から受け取る
-fn create_song_info(dirname: String, filepath: String) -> Song {
-    let contents = fs::read_to_string(&filepath).expect("file open error");
+fn read_props(filepath: &str) -> HashMap<String, String> {
+    parse_props(&fs::read_to_string(filepath).expect("file open error"))
+}
+
+fn parse_props(contents: &str) -> HashMap<String, String> {
     // remove comment
     let statements_without_comment: Vec<&str> = contents
         .split('\n')
@@ -46,7 +77,6 @@ fn create_song_info(dirname: String, filepath: String) -> Song {
     let contents_without_comment: String = statements_without_comment.join("\n");
     let statements = contents_without_comment.split(';');
     let mut props = HashMap::new();
-    let mut notes_strings = Vec::new();
     for statement in statements {
         let parts: Vec<&str> = statement.trim().split(':').collect();
         if parts.len() < 2 {
@@ -54,22 +84,29 @@ fn create_song_info(dirname: String, filepath: String) -> Song {
         }
         let key = parts[0].trim_matches('#');
         let value = parts[1..].join(":");
-        if key == "NOTES" {
-            notes_strings.push(value.to_string());
-        } else {
+        if key != "NOTES" {
             props.insert(key.to_string(), value.to_string());
         }
     }
+    props
+}
 
-    let charts = chart::create_chart(&filepath);
+fn create_song_info(
+    dirname: String,
+    filepath: &str,
+    props: &HashMap<String, String>,
+    assets: &assets::Assets,
+    audio: Option<audio::AudioInfo>,
+    charts: &[chart::Chart],
+) -> Song {
     // TODO: .ssc形式に対応するなら、BPM情報はChartInfoに含まれるべき
     let bpms: Vec<f32> = charts[0].content.gimmick.soflan.iter().map(|s| s.bpm).collect();
 
     let displaybpm: String = match props.get("DISPLAYBPM") {
         Some(s) => get_disp_bpm(s),
         None => {
-            let max = bpms.iter().max_by(|a, b| a.partial_cmp(&b).unwrap()).unwrap();
-            let min = bpms.iter().min_by(|a, b| a.partial_cmp(&b).unwrap()).unwrap();
+            let max = bpms.iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
+            let min = bpms.iter().min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
             if (max - min).abs() < 0.1 {
                 max.round().to_string()
             } else {
@@ -78,32 +115,79 @@ fn create_song_info(dirname: String, filepath: String) -> Song {
             }
         }
     };
-    let metadata = fs::metadata(&filepath).unwrap();
+    let metadata = fs::metadata(filepath).unwrap();
     let time = FileTime::from_last_modification_time(&metadata).seconds();
     let d = UNIX_EPOCH + Duration::from_secs(time as u64);
     let timestamp = DateTime::<chrono::Local>::from(d).format("%Y-%m-%d %H:%M:%S").to_string();
+    // 見つからなかった場合は書かれていたパスのまま出力する(.dwiではFILE)
+    let music_path = assets.music.clone().unwrap_or_else(|| props.get("MUSIC").or_else(|| props.get("FILE")).cloned().unwrap_or_default());
+    let offset = music_offset(props);
+    if let Some(info) = &audio {
+        // 変換した譜面は元の譜面と同じなので除く
+        for chart in charts.iter().filter(|c| c.info.transforms.is_empty()) {
+            let end = chart.end_time() - offset;
+            if end > info.duration {
+                eprintln!(
+                    "WARNING: {}: {:?} {:?} ends at {:.2}s but the music is {:.2}s long",
+                    filepath, chart.info.chart_type, chart.info.difficulty, end, info.duration
+                );
+            }
+        }
+    }
     Song {
         title: props.get("TITLE").unwrap().to_string(),
         dir_name: dirname,
-        charts: charts.iter().map(|chart| chart.info).collect(),
+        charts: charts.iter().map(|chart| chart.info.clone()).collect(),
         bpm: displaybpm,
         music: Music {
-            path: props.get("MUSIC").unwrap().to_string(),
-            // 良い書き方がありそう
-            offset: if let Some(ofs) = props.get("OFFSET") {
-                ofs.parse().unwrap()
-            } else {
-                0.0
-            }
+            path: music_path,
+            offset,
+            audio,
         },
-        banner: props.get("BANNER").unwrap().to_string(),
+        banner: assets.banner.clone().or_else(|| props.get("BANNER").cloned()).unwrap_or_default(),
+        background: assets.background.clone().or_else(|| props.get("BACKGROUND").cloned()).unwrap_or_default(),
+        jacket: assets.jacket.clone().or_else(|| props.get("JACKET").cloned()).unwrap_or_default(),
+        banner_thumbnail: None,
+        preview: None,
         timestamp,
     }
 }
 
+fn music_offset(props: &HashMap<String, String>) -> f32 {
+    // 良い書き方がありそう
+    if let Some(ofs) = props.get("OFFSET") {
+        ofs.parse().unwrap()
+    } else if let Some(gap) = props.get("GAP") {
+        // .dwiのGAPはミリ秒で符号が逆
+        -gap.parse::<f32>().unwrap() / 1000.0
+    } else {
+        0.0
+    }
+}
+
+// 曲の終わり(譜面の0拍目からの秒)。LASTSECONDHINT、指定されていれば音声ファイルの長さの順に使う
+// どちらも音声ファイルの時間なので、0拍目の位置(OFFSET)で譜面の時間に直す
+fn music_end(props: &HashMap<String, String>, audio: Option<&audio::AudioInfo>, from_audio: bool) -> Option<f32> {
+    let offset = music_offset(props);
+    let hint = props.get("LASTSECONDHINT").and_then(|s| s.trim().parse::<f32>().ok()).filter(|s| *s > 0.0);
+    hint.or_else(|| audio.filter(|_| from_audio).map(|a| a.duration)).map(|end| end + offset)
+}
+
+// 音声ファイルのヘッダを読む。曲ごとに一度だけ呼ぶ
+// 見つからないことはresolve_assetsで報告している
+fn read_music_info(filepath: &str, assets: &assets::Assets) -> Option<audio::AudioInfo> {
+    let music = assets.music.as_ref()?;
+    audio::read_audio_info(&Path::new(filepath).with_file_name(music))
+        .map_err(|e| eprintln!("WARNING: {}", e))
+        .ok()
+}
+
 fn get_disp_bpm(s: &str) -> String {
-    let split: Vec<&str> = s.split(':').collect();
-    if split.len() == 1 {
+    // .dwiでは "100..200" のように書く
+    let split: Vec<&str> = s.split(':').flat_map(|s| s.split("..")).collect();
+    if s == "*" {
+        s.to_string()
+    } else if split.len() == 1 {
         split[0].parse::<f32>().unwrap().round().to_string()
     } else {
         let min = split[0].parse::<f32>().unwrap();
@@ -112,12 +196,306 @@ fn get_disp_bpm(s: &str) -> String {
     }
 }
 
+// .sm <-> .ssc の変換。出力先を省略した場合は拡張子を入れ替えたパスに書き出す
+fn convert(args: &[String]) {
+    if args.is_empty() {
+        println!("usage: sm_to_json convert <input> [output]");
+        return;
+    }
+    let input = &args[0];
+    let output = match args.get(1) {
+        Some(s) => s.clone(),
+        None => match simfile::Format::from_path(input) {
+            Some(simfile::Format::Sm) => Path::new(input).with_extension("ssc").to_str().unwrap().to_string(),
+            Some(simfile::Format::Ssc) => Path::new(input).with_extension("sm").to_str().unwrap().to_string(),
+            None => {
+                println!("unsupported file format: {}", input);
+                return;
+            }
+        },
+    };
+    let simfile = simfile::Simfile::load(input);
+    let contents = match simfile::Format::from_path(&output) {
+        Some(simfile::Format::Ssc) => simfile.to_ssc(),
+        Some(simfile::Format::Sm) => {
+            let (contents, warnings) = simfile.to_sm();
+            for warning in warnings {
+                eprintln!("WARNING: {}: {}", input, warning);
+            }
+            contents
+        }
+        None => {
+            println!("unsupported file format: {}", output);
+            return;
+        }
+    };
+    fs::write(&output, contents).unwrap();
+    println!("{} -> {}", input, output);
+}
+
+// ディレクトリは再帰的にたどって.sm/.sscを集める
+fn collect_simfiles(path: &Path, files: &mut Vec<String>) {
+    if path.is_dir() {
+        let mut entries: Vec<_> = match fs::read_dir(path) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+            Err(e) => {
+                eprintln!("WARNING: {}: {}", path.display(), e);
+                return;
+            }
+        };
+        entries.sort();
+        for entry in entries {
+            collect_simfiles(&entry, files);
+        }
+    } else if let Some(path) = path.to_str().filter(|p| simfile::Format::from_path(p).is_some()) {
+        files.push(path.to_string());
+    }
+}
+
+// 変換せずに譜面の問題を報告する。問題があれば終了コード1
+fn lint(args: &[String]) {
+    let json = args.iter().any(|a| a == "--json");
+    let paths: Vec<&String> = args.iter().filter(|a| *a != "--json").collect();
+    if paths.is_empty() {
+        println!("usage: sm_to_json lint <file|dir>... [--json]");
+        return;
+    }
+    let mut files = Vec::new();
+    for path in paths {
+        collect_simfiles(Path::new(path), &mut files);
+    }
+    let issues: Vec<lint::LintIssue> = files.iter().flat_map(|f| lint::lint_file(f)).collect();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&issues).unwrap());
+    } else {
+        for issue in &issues {
+            println!("{}: {}: {}", issue.location(), issue.code, issue.message);
+        }
+    }
+    if !issues.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+// 機械的に直せる問題を直して書き戻す。元のファイルは.bakとして残す
+// .bakが既にあれば最初の版を残すために上書きしない
+fn fix(args: &[String]) {
+    if args.is_empty() {
+        println!("usage: sm_to_json fix <file|dir>...");
+        return;
+    }
+    let mut files = Vec::new();
+    for path in args {
+        collect_simfiles(Path::new(path), &mut files);
+    }
+    for file in files {
+        let mut simfile = simfile::Simfile::load(&file);
+        let changes = fix::fix_simfile(&mut simfile);
+        if changes.is_empty() {
+            println!("{}: no changes", file);
+            continue;
+        }
+        let contents = match simfile::Format::from_path(&file) {
+            Some(simfile::Format::Ssc) => simfile.to_ssc(),
+            _ => {
+                let (contents, warnings) = simfile.to_sm();
+                for warning in warnings {
+                    eprintln!("WARNING: {}: {}", file, warning);
+                }
+                contents
+            }
+        };
+        let backup = format!("{}.bak", file);
+        if Path::new(&backup).exists() {
+            println!("{}: {} already exists, keeping it", file, backup);
+        } else if let Err(e) = fs::copy(&file, &backup) {
+            eprintln!("WARNING: {}: {}", backup, e);
+            continue;
+        }
+        fs::write(&file, contents).unwrap();
+        for change in changes {
+            println!("{}: {}", file, change);
+        }
+    }
+}
+
+// 2つの版の譜面を内容で比べる。違いがあれば終了コード1
+fn diff(args: &[String]) {
+    let json = args.iter().any(|a| a == "--json");
+    let paths: Vec<&String> = args.iter().filter(|a| *a != "--json").collect();
+    if paths.len() != 2 || paths.iter().any(|p| simfile::Format::from_path(p).is_none()) {
+        println!("usage: sm_to_json diff <old.sm|ssc> <new.sm|ssc> [--json]");
+        return;
+    }
+    // レーダーは曲リストと同じく#LASTSECONDHINTを使って計算する
+    // ファイルは一度だけ読み、タグ、曲情報、譜面をそれぞれ同じ内容から作る
+    let load = |path: &String| {
+        let contents = fs::read_to_string(path).expect("file open error");
+        let format = simfile::Format::from_path(path).unwrap();
+        let props = parse_props(&contents);
+        let length = groove_radar::MusicLength { end: music_end(&props, None, false), tail: groove_radar::DEFAULT_TAIL };
+        let mut charts = chart::simfile_to_chart(&contents, format);
+        for chart in charts.iter_mut() {
+            chart.music_length = length;
+            chart.update_groove_radar(groove_radar::RadarEra::X, false);
+        }
+        (simfile::Simfile::parse(&contents, format), charts)
+    };
+    let (old, old_charts) = load(paths[0]);
+    let (new, new_charts) = load(paths[1]);
+    let result = diff::diff_simfiles(&old, &old_charts, &new, &new_charts);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&result).unwrap());
+    } else {
+        for line in result.to_lines() {
+            println!("{}", line);
+        }
+    }
+    if !result.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+// dance-single以外は種類も含めないと同じ難易度の譜面が上書きされる
+// 速さを変えた譜面は"Hard@1.2x.json"のようにする
+// 変換した譜面は"Hard+mirror+shuffle-42.json"のように適用したmodifierを付ける
+fn chart_file_name(info: &chart::ChartInfo, rate: Option<f32>) -> String {
+    let transforms: String = info.transforms.iter().map(|t| format!("+{}", t.replace(':', "-"))).collect();
+    let rate = rate.map_or(String::new(), |r| format!("@{}x", r));
+    if info.chart_type == chart::ChartType::DanceSingle {
+        format!("{:?}{}{}.json", info.difficulty, transforms, rate)
+    } else {
+        format!("{:?}_{:?}{}{}.json", info.chart_type, info.difficulty, transforms, rate)
+    }
+}
+
+// 曲リスト作成時のオプション
+struct DumpOptions {
+    root: String,
+    // 指定された場合だけNPSを計算し直す
+    density: Option<density::DensityConfig>,
+    combo_rule: step_count::ComboRule,
+    radar: groove_radar::RadarEra,
+    radar_detail: bool,
+    radar_tail: f32,
+    length_from_audio: bool,
+    // Noneならコピーしない
+    export: Option<export::ExportOptions>,
+    // 別の曲ディレクトリにある同じ譜面をduplicates.jsonに書き出す
+    dedupe: bool,
+    // 譜面に順に適用する変換
+    transforms: Vec<transform::Transform>,
+    // 元の譜面とは別に、音楽の速さを変えた譜面も出力する
+    rates: Vec<f32>,
+    // ハイスピードを選ぶときの目標の速さ(BPM×倍率)
+    read_speed: f32,
+}
+
+fn parse_dump_options(args: &[String]) -> Result<DumpOptions, String> {
+    let mut root = None;
+    let mut density: Option<density::DensityConfig> = None;
+    let mut combo_rule = step_count::ComboRule::Ddr;
+    let mut radar = groove_radar::RadarEra::X;
+    let mut radar_detail = false;
+    let mut radar_tail = groove_radar::DEFAULT_TAIL;
+    let mut length_from_audio = false;
+    let mut export: Option<export::ExportOptions> = None;
+    let mut dedupe = false;
+    let mut transforms = Vec::new();
+    let mut rates = Vec::new();
+    let mut read_speed = scroll_speed::DEFAULT_READ_SPEED;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--nps-window" => {
+                let value = args.next().ok_or("--nps-window needs seconds")?;
+                let window = value.parse::<f32>().map_err(|e| format!("{}: {}", value, e))?;
+                density.get_or_insert_with(Default::default).window = window;
+            }
+            "--nps-sustain" => {
+                let value = args.next().ok_or("--nps-sustain needs comma separated seconds")?;
+                let windows = value
+                    .split(',')
+                    .map(|s| s.parse::<f32>().map_err(|e| format!("{}: {}", s, e)))
+                    .collect::<Result<Vec<f32>, String>>()?;
+                density.get_or_insert_with(Default::default).sustained_windows = windows;
+            }
+            "--combo-rule" => {
+                combo_rule = args.next().ok_or("--combo-rule needs ddr, ddr-classic or per-note")?.parse()?;
+            }
+            "--radar" => {
+                radar = args.next().ok_or("--radar needs x")?.parse()?;
+            }
+            "--radar-detail" => radar_detail = true,
+            "--radar-tail" => {
+                let value = args.next().ok_or("--radar-tail needs seconds")?;
+                radar_tail = value.parse::<f32>().map_err(|e| format!("{}: {}", value, e))?;
+            }
+            "--length-from-audio" => length_from_audio = true,
+            "--export-assets" => {
+                export.get_or_insert_with(Default::default);
+            }
+            "--thumbnail" => {
+                let value = args.next().ok_or("--thumbnail needs <width>x<height>")?;
+                let size = value
+                    .split_once('x')
+                    .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+                    .ok_or(format!("{}: expected <width>x<height>", value))?;
+                export.get_or_insert_with(Default::default).thumbnail = Some(size);
+            }
+            "--preview-clip" => export.get_or_insert_with(Default::default).preview = true,
+            "--dedupe" => dedupe = true,
+            "--transform" => {
+                let value = args.next().ok_or("--transform needs comma separated transforms")?;
+                transforms = value.split(',').map(|s| s.parse()).collect::<Result<Vec<transform::Transform>, String>>()?;
+            }
+            "--rates" => {
+                let value = args.next().ok_or("--rates needs comma separated rates")?;
+                rates = value
+                    .split(',')
+                    .map(|s| s.trim_end_matches('x').parse::<f32>().map_err(|e| format!("{}: {}", s, e)))
+                    .collect::<Result<Vec<f32>, String>>()?;
+            }
+            "--read-speed" => {
+                let value = args.next().ok_or("--read-speed needs bpm")?;
+                read_speed = value.parse::<f32>().map_err(|e| format!("{}: {}", value, e))?;
+            }
+            _ if root.is_none() => root = Some(arg.clone()),
+            _ => return Err(format!("unknown argument: {}", arg)),
+        }
+    }
+    if density.as_ref().is_some_and(|d| d.window <= 0.0 || d.sustained_windows.iter().any(|w| *w <= 0.0)) {
+        return Err("NPS windows must be positive".to_string());
+    }
+    if rates.iter().any(|r| *r <= 0.0) {
+        return Err("rates must be positive".to_string());
+    }
+    if read_speed <= 0.0 {
+        return Err("read speed must be positive".to_string());
+    }
+    Ok(DumpOptions {
+        root: root.ok_or("no songs directory")?,
+        density,
+        combo_rule,
+        radar,
+        radar_detail,
+        radar_tail,
+        length_from_audio,
+        export,
+        dedupe,
+        transforms,
+        rates,
+        read_speed,
+    })
+}
+
 // TODO: 1つの.smファイルを1つのjsonにしたほうが楽そう
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    match fs::read_dir(args[1].clone()) {
+fn dump_songs(options: &DumpOptions) {
+    match fs::read_dir(&options.root) {
         Ok(dirs) => {
             let mut songs = Vec::new();
+            let mut asset_reports = Vec::new();
+            let mut hashes = Vec::new();
             for dir in dirs.into_iter().filter(|dir| dir.as_ref().unwrap().path().is_dir()) {
                 let dir = dir.unwrap();
                 let dirname = dir.file_name().into_string().unwrap();
@@ -128,7 +506,7 @@ fn main() {
                         for file in dirs {
                             let file = file.unwrap();
                             let filename = file.file_name().into_string().unwrap();
-                            if filename.ends_with(".sm") || filename.ends_with(".ssc") {
+                            if [".sm", ".ssc", ".dwi", ".ksf", ".osu", ".bms", ".bme", ".bhe"].iter().any(|ext| filename.ends_with(ext)) {
                                 let path = Path::new(&dir.path()).join(filename);
                                 files.push(path.to_str().unwrap().to_string());
                             }
@@ -138,31 +516,160 @@ fn main() {
                         println!("failed to read_dir for {:?}: {:?}",dir, e);
                     }
                 }
+                // .ksf, .osu, BMSは1ファイル1譜面なので、ディレクトリ内の全ファイルで1曲とする
+                let (ksf_files, files): (Vec<String>, Vec<String>) = files.into_iter().partition(|f| f.ends_with(".ksf"));
+                let (osu_files, files): (Vec<String>, Vec<String>) = files.into_iter().partition(|f| f.ends_with(".osu"));
+                let (bms_files, files): (Vec<String>, Vec<String>) = files.into_iter().partition(|f| !f.ends_with(".sm") && !f.ends_with(".ssc") && !f.ends_with(".dwi"));
+                let mut song_files: Vec<(String, HashMap<String, String>, Vec<chart::Chart>)> = files
+                    .into_iter()
+                    .map(|file| {
+                        println!("file: {}", file);
+                        let props = read_props(&file);
+                        let charts = chart::create_chart(&file);
+                        (file, props, charts)
+                    })
+                    .collect();
+                for (chart_files, read_props) in [(ksf_files, ksf::ksf_props as fn(&str) -> HashMap<String, String>), (osu_files, osu::osu_props), (bms_files, bms::bms_props)] {
+                    if chart_files.is_empty() {
+                        continue;
+                    }
+                    println!("file: {}", chart_files.join(", "));
+                    let props = read_props(&chart_files[0]);
+                    let mut charts: Vec<chart::Chart> = Vec::new();
+                    for (chart_file, chart) in chart_files.iter().flat_map(|f| chart::create_chart(f).into_iter().map(move |c| (f, c))) {
+                        // 同じ種類と難易度の譜面は出力ファイル名が同じになるので、最初のものだけを使う
+                        let key = (chart.info.chart_type, chart.info.difficulty);
+                        if charts.iter().any(|c| (c.info.chart_type, c.info.difficulty) == key) {
+                            eprintln!("WARNING: {}: {:?} {:?} is already used by another file; skipped", chart_file, key.0, key.1);
+                            continue;
+                        }
+                        charts.push(chart);
+                    }
+                    if !charts.is_empty() {
+                        song_files.push((chart_files[0].clone(), props, charts));
+                    }
+                }
                 // 各譜面のjsonを作りつつ曲リストに追加していく
-                for file in files {
-                    println!("file: {}", file);
-                    let song = create_song_info(dirname.clone(), file.clone());
-                    let dir_path = Path::new("output").join(&dir.path());
+                for (file, props, mut charts) in song_files {
+                    // 変換した譜面は元の譜面とは別に出力する
+                    if !options.transforms.is_empty() {
+                        let transformed: Vec<chart::Chart> = charts
+                            .iter()
+                            .filter_map(|chart| {
+                                transform::transform_chart(chart, &options.transforms, options.radar, options.radar_detail)
+                                    .map_err(|e| eprintln!("WARNING: {}: {:?} {:?}: {}", file, chart.info.chart_type, chart.info.difficulty, e))
+                                    .ok()
+                            })
+                            .collect();
+                        charts.extend(transformed);
+                    }
+                    if let Some(config) = &options.density {
+                        charts.iter_mut().for_each(|c| c.update_density(config));
+                    }
+                    charts.iter_mut().for_each(|c| c.update_max_combo(options.combo_rule));
+                    charts.iter_mut().for_each(|c| c.update_scroll_speed(options.read_speed));
+                    let assets = assets::resolve_assets(&file, &props);
+                    for missing in &assets.missing {
+                        eprintln!("WARNING: {}: {}", file, missing);
+                    }
+                    let audio = read_music_info(&file, &assets);
+                    let length = groove_radar::MusicLength {
+                        end: music_end(&props, audio.as_ref(), options.length_from_audio),
+                        tail: options.radar_tail,
+                    };
+                    for chart in charts.iter_mut() {
+                        chart.music_length = length;
+                        chart.update_groove_radar(options.radar, options.radar_detail);
+                    }
+                    // 速さを変えた譜面も元の譜面と同じ設定で計算し直す
+                    let mut rated_charts = Vec::new();
+                    for chart in charts.iter_mut() {
+                        for rate in &options.rates {
+                            let mut rated = transform::rate_chart(chart, *rate, options.radar, options.radar_detail);
+                            if let Some(config) = &options.density {
+                                rated.update_density(config);
+                            }
+                            rated.update_max_combo(options.combo_rule);
+                            chart.info.rates.push(chart::RatedChartInfo::from_info(*rate, &rated.info));
+                            rated_charts.push((*rate, rated));
+                        }
+                    }
+                    let mut song = create_song_info(dirname.clone(), &file, &props, &assets, audio, &charts);
+                    if !assets.missing.is_empty() {
+                        asset_reports.push(assets::AssetReport { dir_name: dirname.clone(), file: file.clone(), missing: assets.missing.clone() });
+                    }
+                    let dir_path = Path::new("output").join(dir.path());
                     fs::create_dir_all(&dir_path).unwrap();
-                    let charts = chart::create_chart(&file);
+                    // コピーしたものはsongs.jsonのパスを書き換える
+                    if let Some(export_options) = &options.export {
+                        let exported = export::export_assets(&file, &dir_path, &assets, &props, export_options);
+                        if let Some(music) = exported.music {
+                            song.music.path = music;
+                        }
+                        for (path, field) in [(exported.banner, &mut song.banner), (exported.background, &mut song.background), (exported.jacket, &mut song.jacket)] {
+                            if let Some(path) = path {
+                                *field = path;
+                            }
+                        }
+                        song.banner_thumbnail = exported.banner_thumbnail;
+                        song.preview = exported.preview;
+                    }
                     // 譜面ごとのjsonを作成
-                    for chart in &charts {
+                    for (rate, chart) in charts.iter().map(|c| (None, c)).chain(rated_charts.iter().map(|(r, c)| (Some(*r), c))) {
                         let mut chart_path = dir_path.clone();
-                        chart_path.push(format!("{:?}.json", chart.info.difficulty));
+                        chart_path.push(chart_file_name(&chart.info, rate));
                         println!("{:?}", chart_path);
                         let chart_json = serde_json::to_string(&chart.content).unwrap();
                         fs::write(chart_path, chart_json).unwrap();
                     }
 
+                    if options.dedupe {
+                        for chart in charts.iter().filter(|c| c.info.transforms.is_empty()) {
+                            let location = chart_hash::ChartLocation {
+                                dir_name: dirname.clone(),
+                                file: file.clone(),
+                                chart_type: chart.info.chart_type,
+                                difficulty: chart.info.difficulty,
+                            };
+                            hashes.push((chart.info.hash.clone(), location));
+                        }
+                    }
+
                     // 曲リスト更新
                     songs.push(song);
                 }
             }
             let j = serde_json::to_string(&songs).unwrap();
             fs::write(Path::new("output").join("songs.json"), j).unwrap();
+            let report = serde_json::to_string_pretty(&asset_reports).unwrap();
+            fs::write(Path::new("output").join("asset_report.json"), report).unwrap();
+            if options.dedupe {
+                let duplicates = chart_hash::find_duplicates(hashes);
+                for duplicate in &duplicates {
+                    let files: Vec<&str> = duplicate.charts.iter().map(|c| c.file.as_str()).collect();
+                    println!("duplicate chart {}: {}", duplicate.hash, files.join(", "));
+                }
+                let report = serde_json::to_string_pretty(&duplicates).unwrap();
+                fs::write(Path::new("output").join("duplicates.json"), report).unwrap();
+            }
         }
         Err(e) => {
             println!("failed to open root directory: {:?}", e);
         }
     }
 }
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(|s| s.as_str()) {
+        Some("convert") => convert(&args[2..]),
+        Some("lint") => lint(&args[2..]),
+        Some("fix") => fix(&args[2..]),
+        Some("diff") => diff(&args[2..]),
+        Some(_) => match parse_dump_options(&args[1..]) {
+            Ok(options) => dump_songs(&options),
+            Err(e) => println!("{}", e),
+        },
+        None => println!("usage: sm_to_json <songs_dir> [--nps-window <sec>] [--nps-sustain <sec,...>] [--combo-rule <rule>] [--radar <model>] [--radar-detail] [--radar-tail <sec>] [--length-from-audio] [--export-assets [--thumbnail <w>x<h>] [--preview-clip]] [--dedupe] [--transform <mod,...>] [--rates <rate,...>] [--read-speed <bpm>] | sm_to_json convert <input> [output] | sm_to_json lint <file|dir>... [--json] | sm_to_json fix <file|dir>... | sm_to_json diff <old> <new> [--json]"),
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use crate::arrow::NOTE_UNIT;
+use crate::chart::{Chart, ChartType, Difficulty, make_chart, rows_to_bars, str_to_notes};
+use crate::gimmick::{Bpm, TimeSignature};
+
+// osu!mania形式(.osu)
+// 1ファイル1譜面で、[TimingPoints]と[HitObjects]の時刻はミリ秒
+// 列は x * キー数 / 512 で決まる。4Kはdance-single、7Kはkb7-singleとして扱う
+
+// 192分のグリッドに丸めた時のずれがこれ以上なら報告する
+const SNAP_TOLERANCE_MS: f64 = 1.0;
+const HOLD_TYPE: i32 = 128;
+const UNITS_PER_BEAT: f64 = (NOTE_UNIT / 4) as f64;
+
+struct OsuFile {
+    values: HashMap<String, String>,
+    background: Option<String>,
+    // (時刻, 1拍の長さ, 1小節の拍数) uninheritedなものだけ
+    timing_points: Vec<(f64, f64, i32)>,
+    // (時刻, 列, 終点の時刻)
+    hit_objects: Vec<(f64, usize, Option<f64>)>,
+}
+
+fn parse_osu(contents: &str) -> OsuFile {
+    let mut values = HashMap::new();
+    let mut background = None;
+    let mut timing_points = Vec::new();
+    let mut raw_objects: Vec<(f64, i32, i32, Option<f64>)> = Vec::new();
+    let mut section = "";
+    for line in contents.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with("//")) {
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line.trim_matches(|c| c == '[' || c == ']');
+            continue;
+        }
+        match section {
+            "General" | "Metadata" | "Difficulty" => {
+                if let Some((key, value)) = line.split_once(':') {
+                    values.insert(key.trim().to_string(), value.trim().to_string());
+                }
+            }
+            "Events" => {
+                let fields: Vec<&str> = line.split(',').collect();
+                if fields.len() >= 3 && fields[0] == "0" && background.is_none() {
+                    background = Some(fields[2].trim_matches('"').to_string());
+                }
+            }
+            "TimingPoints" => {
+                let fields: Vec<&str> = line.split(',').collect();
+                let time: f64 = fields[0].trim().parse().unwrap();
+                let beat_length: f64 = fields[1].trim().parse().unwrap();
+                let meter: i32 = fields.get(2).map_or(4, |s| s.trim().parse().unwrap_or(4));
+                let uninherited = fields.get(6).is_none_or(|s| s.trim() == "1");
+                if uninherited && beat_length > 0.0 {
+                    timing_points.push((time, beat_length, meter));
+                }
+            }
+            "HitObjects" => {
+                let fields: Vec<&str> = line.split(',').collect();
+                let x: i32 = fields[0].trim().parse().unwrap();
+                let time: f64 = fields[2].trim().parse().unwrap();
+                let object_type: i32 = fields[3].trim().parse().unwrap();
+                let end = if object_type & HOLD_TYPE != 0 {
+                    fields.get(5).and_then(|s| s.split(':').next()).map(|s| s.trim().parse().unwrap())
+                } else {
+                    None
+                };
+                raw_objects.push((time, x, object_type, end));
+            }
+            _ => {}
+        }
+    }
+    let keys: i32 = values.get("CircleSize").and_then(|s| s.parse::<f32>().ok()).map_or(4, |k| k as i32);
+    let hit_objects = raw_objects
+        .into_iter()
+        .map(|(time, x, _, end)| (time, ((x * keys / 512).clamp(0, keys - 1)) as usize, end))
+        .collect();
+    timing_points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    OsuFile { values, background, timing_points, hit_objects }
+}
+
+// BPM区間ごとに (開始時刻, 開始位置(1拍=48), 1拍の長さ)
+struct Timing {
+    segments: Vec<(f64, f64, f64)>,
+    time_signatures: Vec<TimeSignature>,
+}
+
+impl Timing {
+    // 最初のノーツが0以上の位置になるように、最初のtiming pointより前に小節を足す
+    // timing pointが無ければNone
+    fn new(timing_points: &[(f64, f64, i32)], first_note: f64) -> Option<Timing> {
+        let &(first_time, first_length, first_meter) = timing_points.first()?;
+        let measure = first_length * first_meter as f64;
+        let measures_before = ((first_time - first_note) / measure).ceil().max(0.0);
+        let origin = first_time - measures_before * measure;
+        let mut segments: Vec<(f64, f64, f64)> = vec![(origin, 0.0, first_length)];
+        let mut time_signatures: Vec<TimeSignature> = Vec::new();
+        for &(time, length, meter) in timing_points {
+            let (prev_time, prev_pos, prev_length) = *segments.last().unwrap();
+            let pos = prev_pos + (time - prev_time) / prev_length * UNITS_PER_BEAT;
+            segments.push((time, pos, length));
+            // osu!では拍子は n/4 のみ
+            if time_signatures.last().is_none_or(|t| t.numerator != meter) {
+                time_signatures.push(TimeSignature {
+                    offset: if time_signatures.is_empty() { 0 } else { pos.round() as i32 },
+                    numerator: meter,
+                    denominator: 4,
+                });
+            }
+        }
+        Some(Timing { segments, time_signatures })
+    }
+
+    fn origin(&self) -> f64 {
+        self.segments[0].0
+    }
+
+    fn time_to_position(&self, time: f64) -> f64 {
+        let (start, pos, length) = *self
+            .segments
+            .iter()
+            .rev()
+            .find(|(start, _, _)| *start <= time)
+            .unwrap_or(&self.segments[0]);
+        pos + (time - start) / length * UNITS_PER_BEAT
+    }
+
+    fn position_to_time(&self, position: f64) -> f64 {
+        let (start, pos, length) = *self
+            .segments
+            .iter()
+            .rev()
+            .find(|(_, pos, _)| *pos <= position)
+            .unwrap_or(&self.segments[0]);
+        start + (position - pos) / UNITS_PER_BEAT * length
+    }
+
+    fn bpms(&self) -> Vec<Bpm> {
+        let mut bpms: Vec<Bpm> = Vec::new();
+        for &(_, pos, length) in self.segments.iter().skip(1) {
+            let bpm = (60000.0 / length) as f32;
+            let offset = pos.round() as i32;
+            match bpms.last_mut() {
+                Some(last) if last.offset == offset => last.bpm = bpm,
+                Some(last) if (last.bpm - bpm).abs() < 0.001 => {}
+                _ => bpms.push(Bpm { offset, bpm }),
+            }
+        }
+        bpms[0].offset = 0;
+        bpms
+    }
+}
+
+// 192分のグリッドに丸める。ずれが大きい場合はずれ(ms)も返す
+fn quantize(timing: &Timing, time: f64) -> (i32, Option<f64>) {
+    let offset = timing.time_to_position(time).round() as i32;
+    let error = (timing.position_to_time(offset as f64) - time).abs();
+    (offset, if error > SNAP_TOLERANCE_MS { Some(error) } else { None })
+}
+
+fn parse_difficulty(version: &str) -> Difficulty {
+    let version = version.to_lowercase();
+    if version.contains("beginner") {
+        Difficulty::Beginner
+    } else if version.contains("easy") || version.contains("basic") {
+        Difficulty::Easy
+    } else if version.contains("normal") || version.contains("medium") {
+        Difficulty::Medium
+    } else if version.contains("hard") || version.contains("hyper") {
+        Difficulty::Hard
+    } else if version.contains("insane") || version.contains("another") || version.contains("expert") {
+        Difficulty::Challenge
+    } else {
+        Difficulty::Edit
+    }
+}
+
+pub fn osu_to_chart(filepath: &String) -> Vec<Chart> {
+    let contents = fs::read_to_string(filepath).expect("file open error");
+    let osu = parse_osu(&contents);
+    if osu.values.get("Mode").map(|s| s.as_str()) != Some("3") {
+        eprintln!("WARNING: {}: not an osu!mania beatmap; skipped", filepath);
+        return Vec::new();
+    }
+    let chart_type = match osu.values.get("CircleSize").map(|s| s.as_str()) {
+        Some("4") => ChartType::DanceSingle,
+        Some("7") => ChartType::Kb7Single,
+        keys => {
+            eprintln!("WARNING: {}: {:?}K is not supported; skipped", filepath, keys);
+            return Vec::new();
+        }
+    };
+    if osu.hit_objects.is_empty() {
+        eprintln!("WARNING: {}: no notes in [HitObjects]; skipped", filepath);
+        return Vec::new();
+    }
+    let width = chart_type.directions().len();
+    let first_note = osu.hit_objects.iter().map(|o| o.0).fold(f64::MAX, f64::min);
+    let Some(timing) = Timing::new(&osu.timing_points, first_note) else {
+        eprintln!("WARNING: {}: no uninherited timing points in [TimingPoints]; skipped", filepath);
+        return Vec::new();
+    };
+
+    let mut rows: BTreeMap<i32, String> = BTreeMap::new();
+    let mut put = |offset: i32, column: usize, c: char, time: f64| {
+        let row = rows.entry(offset).or_insert_with(|| "0".repeat(width));
+        if row.as_bytes()[column] != b'0' {
+            eprintln!("WARNING: {}: note at {}ms overlaps another note after quantization", filepath, time);
+            return;
+        }
+        row.replace_range(column..column + 1, &c.to_string());
+    };
+    for &(time, column, end) in &osu.hit_objects {
+        let (offset, error) = quantize(&timing, time);
+        if let Some(error) = error {
+            eprintln!("WARNING: {}: note at {}ms cannot snap to 1/192 (off by {:.2}ms)", filepath, time, error);
+        }
+        match end {
+            Some(end) => {
+                let (end_offset, _) = quantize(&timing, end);
+                put(offset, column, '2', time);
+                put(end_offset.max(offset + 1), column, '3', end);
+            }
+            None => put(offset, column, '1', time),
+        }
+    }
+
+    let bpms = timing.bpms();
+    let difficulty = parse_difficulty(osu.values.get("Version").map_or("", |s| s.as_str()));
+    // .osuにはレベルに当たる値が無い(OverallDifficultyは判定の厳しさ)ので0(不明)にする
+    let level = 0;
+    let bars = rows_to_bars(&rows, width);
+    let notes = str_to_notes(bars.iter().map(|s| s.as_str()).collect(), chart_type, &bpms, &[], &timing.time_signatures);
+    vec![make_chart(chart_type, difficulty, level, notes, &bpms, &[], &timing.time_signatures)]
+}
+
+// 曲情報を.smと同じキー(TITLE, MUSIC, BANNER, OFFSET)で返す
+pub fn osu_props(filepath: &str) -> HashMap<String, String> {
+    let contents = fs::read_to_string(filepath).expect("file open error");
+    let osu = parse_osu(&contents);
+    let mut props = HashMap::new();
+    if let Some(title) = osu.values.get("TitleUnicode").or_else(|| osu.values.get("Title")) {
+        props.insert("TITLE".to_string(), title.clone());
+    }
+    if let Some(music) = osu.values.get("AudioFilename") {
+        props.insert("MUSIC".to_string(), music.clone());
+    }
+    if let Some(background) = osu.background {
+        props.insert("BANNER".to_string(), background);
+    }
+    let first_note = osu.hit_objects.iter().map(|o| o.0).fold(f64::MAX, f64::min);
+    if let Some(timing) = Timing::new(&osu.timing_points, first_note) {
+        props.insert("OFFSET".to_string(), (-timing.origin() / 1000.0).to_string());
+    }
+    props
+}
+
+#[test]
+fn test_quantize() {
+    // 120BPM(1拍500ms)で始まり、2000msから240BPM
+    let timing = Timing::new(&[(1000.0, 500.0, 4), (2000.0, 250.0, 3)], 1000.0).unwrap();
+    assert_eq!(quantize(&timing, 1000.0), (0, None));
+    assert_eq!(quantize(&timing, 1250.0), (24, None));
+    assert_eq!(quantize(&timing, 2125.0), (120, None));
+    assert_eq!(quantize(&timing, 1003.0).0, 0);
+    assert!(quantize(&timing, 1003.0).1.is_some());
+    let bpms = timing.bpms();
+    assert_eq!(bpms.len(), 2);
+    assert_eq!((bpms[1].offset, bpms[1].bpm), (96, 240.0));
+    // 最初のノーツがtiming pointより前なら1小節前から始める
+    assert_eq!(Timing::new(&[(1000.0, 500.0, 4)], 900.0).unwrap().origin(), -1000.0);
+    assert!(Timing::new(&[], 0.0).is_none());
+    assert_eq!(timing.time_signatures[1], TimeSignature { offset: 96, numerator: 3, denominator: 4 });
+}
+
+#[test]
+fn test_osu_to_chart() {
+    let path = std::env::temp_dir().join(format!("sm_to_json_{}.osu", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let header = "[General]\nMode: 3\n[Metadata]\nVersion:Hard\n[Difficulty]\nCircleSize:4\nOverallDifficulty:8\n";
+    // ノーツもtiming pointも無いものは読み飛ばす
+    std::fs::write(&path, format!("{}[TimingPoints]\n0,500,4,2,0,100,1,0\n[HitObjects]\n", header)).unwrap();
+    assert!(osu_to_chart(&path).is_empty());
+    std::fs::write(&path, format!("{}[HitObjects]\n64,192,0,1,0,0:0:0:0:\n", header)).unwrap();
+    assert!(osu_to_chart(&path).is_empty());
+    // レベルはOverallDifficultyを使わず0にする
+    std::fs::write(&path, format!("{}[TimingPoints]\n0,500,4,2,0,100,1,0\n[HitObjects]\n64,192,0,1,0,0:0:0:0:\n448,192,500,1,0,0:0:0:0:\n", header)).unwrap();
+    let charts = osu_to_chart(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(charts.len(), 1);
+    assert_eq!((charts[0].info.level, charts[0].info.steps.taps), (0, 2));
+}
@@ -0,0 +1,270 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::arrow::{Division, Foot};
+
+// dance-singleの足運び(どちらの足で踏むか)を求める
+// 行ごとに足の位置の状態を持ち、コストが最小になる踏み方を動的計画法で選ぶ
+// パネルは列の順に 0:左 1:下 2:上 3:右 で、足の位置はパネルのビットの組(2つなら踏み分けのbracket)
+
+const PANEL_X: [f32; 4] = [0.0, 1.0, 1.0, 2.0];
+const PANEL_Y: [f32; 4] = [1.0, 0.0, 2.0, 1.0];
+const LEFT_PANEL: u8 = 0b0001;
+const RIGHT_PANEL: u8 = 0b1000;
+// 片足で2つ踏めるのは左右のパネルと上下のパネルの組だけ
+const LEFT_BRACKETS: [u8; 2] = [0b0011, 0b0101];
+const RIGHT_BRACKETS: [u8; 2] = [0b1010, 0b1100];
+const UP_DOWN: u8 = 0b0110;
+
+// コスト
+const CROSSED_COST: f32 = 2.0;
+const SPIN_COST: f32 = 20.0;
+const FACING_COST: f32 = 0.5;
+const DOUBLESTEP_COST: f32 = 6.0;
+const BRACKET_COST: f32 = 1.0;
+const MOVE_COST: f32 = 0.5;
+const RELEASE_HOLD_COST: f32 = 100.0;
+// これより短い間隔の同じパネルは交互に踏む方が楽
+const FAST_INTERVAL: f32 = 0.125;
+const FAST_JACK_COST: f32 = 1.5;
+const FAST_FOOTSWITCH_COST: f32 = 1.0;
+const SLOW_FOOTSWITCH_COST: f32 = 3.0;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ParityCounts {
+    // 左足で右のパネル、または右足で左のパネルを踏んで足が交差した回数
+    pub crossovers: i32,
+    // 上下のパネルを続けて別の足で踏んだ回数
+    pub footswitches: i32,
+    // 左右のパネルを続けて別の足で踏んだ回数
+    pub sideswitches: i32,
+    // 同じパネルを続けて同じ足で踏んだ回数
+    pub jacks: i32,
+    // 別のパネルを続けて同じ足で踏んだ回数(もう片方の足がfreeze中の場合は除く)
+    pub doublesteps: i32,
+    // 片足で2つのパネルを踏んだ回数
+    pub brackets: i32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct FootState {
+    left: u8,
+    right: u8,
+    // freeze/rollで押さえている間は動かせない(終点のoffset、無ければ-1)
+    left_until: i32,
+    right_until: i32,
+    // 直前の行で踏んだ足(bit0: 左, bit1: 右)
+    last: u8,
+}
+
+struct Node {
+    state: FootState,
+    cost: f32,
+    prev: usize,
+    // この行のノーツのうち左足で踏むもの
+    left_notes: u8,
+}
+
+fn center(mask: u8) -> (f32, f32) {
+    let panels: Vec<usize> = (0..4).filter(|p| mask & (1 << p) != 0).collect();
+    let n = panels.len() as f32;
+    (panels.iter().map(|p| PANEL_X[*p]).sum::<f32>() / n, panels.iter().map(|p| PANEL_Y[*p]).sum::<f32>() / n)
+}
+
+fn distance(a: u8, b: u8) -> f32 {
+    let ((ax, ay), (bx, by)) = (center(a), center(b));
+    ((ax - bx).powi(2) + (ay - by).powi(2)).sqrt()
+}
+
+fn is_crossed(left: u8, right: u8) -> bool {
+    center(left).0 > center(right).0
+}
+
+fn valid_foot(notes: u8, brackets: &[u8]) -> bool {
+    notes.count_ones() < 2 || brackets.contains(&notes)
+}
+
+// 1行を踏んだ後の状態とコスト
+fn step(prev: &FootState, offset: i32, interval: f32, left_notes: u8, right_notes: u8, hold_ends: &[i32; 4]) -> Option<(FootState, f32)> {
+    if !valid_foot(left_notes, &LEFT_BRACKETS) || !valid_foot(right_notes, &RIGHT_BRACKETS) {
+        return None;
+    }
+    let mut cost = 0.0;
+    let until = |notes: u8| (0..4).filter(|c| notes & (1 << c) != 0).map(|c| hold_ends[c]).max().unwrap_or(-1);
+    let mut next = FootState { left: prev.left, right: prev.right, left_until: -1, right_until: -1, last: 0 };
+    for (notes, old, busy, new, new_until, bit) in [
+        (left_notes, prev.left, prev.left_until, &mut next.left, &mut next.left_until, 1),
+        (right_notes, prev.right, prev.right_until, &mut next.right, &mut next.right_until, 2),
+    ] {
+        if notes == 0 {
+            *new_until = if busy > offset { busy } else { -1 };
+            continue;
+        }
+        if busy > offset {
+            cost += RELEASE_HOLD_COST;
+        }
+        if notes.count_ones() == 2 {
+            cost += BRACKET_COST;
+        }
+        cost += distance(old, notes) * MOVE_COST;
+        *new = notes;
+        *new_until = until(notes);
+        next.last |= bit;
+    }
+    if next.left & RIGHT_PANEL != 0 && next.right & LEFT_PANEL != 0 {
+        cost += SPIN_COST;
+    } else if is_crossed(next.left, next.right) {
+        cost += CROSSED_COST;
+    } else if center(next.left).0 == center(next.right).0 {
+        cost += FACING_COST;
+    }
+    // 片足だけで踏んだ場合
+    let (notes, old, other_old, other_busy) = match next.last {
+        1 => (left_notes, prev.left, prev.right, prev.right_until),
+        2 => (right_notes, prev.right, prev.left, prev.left_until),
+        _ => return Some((next, cost)),
+    };
+    if prev.last == next.last {
+        if notes == old {
+            if interval < FAST_INTERVAL {
+                cost += FAST_JACK_COST;
+            }
+        } else if other_busy <= offset {
+            cost += DOUBLESTEP_COST;
+        }
+    }
+    if notes & other_old != 0 {
+        cost += if interval < FAST_INTERVAL { FAST_FOOTSWITCH_COST } else { SLOW_FOOTSWITCH_COST };
+    }
+    Some((next, cost))
+}
+
+// 踏むノーツのある行ごとの(ノーツの列のビット, 各列のfreeze/rollの終点)
+fn note_rows(notes: &[Division]) -> Vec<(usize, u8, [i32; 4])> {
+    notes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, d)| {
+            let mut mask = 0;
+            let mut hold_ends = [-1; 4];
+            for arrow in d.arrows.iter().filter(|a| a.is_note() && a.column < 4) {
+                mask |= 1 << arrow.column;
+                if arrow.is_hold() {
+                    hold_ends[arrow.column] = arrow.end;
+                }
+            }
+            (mask != 0).then_some((i, mask, hold_ends))
+        })
+        .collect()
+}
+
+// 各ノーツにfootを書き込み、足運びの種類ごとの回数を返す
+pub fn solve_parity(notes: &mut [Division]) -> ParityCounts {
+    let rows = note_rows(notes);
+    let start = FootState { left: LEFT_PANEL, right: RIGHT_PANEL, left_until: -1, right_until: -1, last: 0 };
+    let mut layers: Vec<Vec<Node>> = vec![vec![Node { state: start, cost: 0.0, prev: 0, left_notes: 0 }]];
+    let mut prev_time = None;
+    for (i, mask, hold_ends) in &rows {
+        let (offset, time) = (notes[*i].offset, notes[*i].time);
+        let interval = prev_time.map_or(f32::MAX, |t| time - t);
+        prev_time = Some(time);
+        let mut layer: Vec<Node> = Vec::new();
+        let mut index: HashMap<FootState, usize> = HashMap::new();
+        for (p, node) in layers.last().unwrap().iter().enumerate() {
+            // maskの部分集合をすべて試す
+            let mut left_notes = *mask;
+            loop {
+                if let Some((state, cost)) = step(&node.state, offset, interval, left_notes, mask & !left_notes, hold_ends) {
+                    let cost = node.cost + cost;
+                    match index.get(&state) {
+                        Some(&j) if layer[j].cost <= cost => {}
+                        Some(&j) => layer[j] = Node { state, cost, prev: p, left_notes },
+                        None => {
+                            index.insert(state, layer.len());
+                            layer.push(Node { state, cost, prev: p, left_notes });
+                        }
+                    }
+                }
+                if left_notes == 0 {
+                    break;
+                }
+                left_notes = (left_notes - 1) & mask;
+            }
+        }
+        layers.push(layer);
+    }
+
+    // コストが最小の状態から逆にたどる
+    let mut path: Vec<&Node> = Vec::new();
+    let last = layers.last().unwrap();
+    let mut j = (0..last.len()).min_by(|a, b| last[*a].cost.total_cmp(&last[*b].cost)).unwrap();
+    for layer in layers.iter().skip(1).rev() {
+        path.push(&layer[j]);
+        j = layer[j].prev;
+    }
+    path.reverse();
+
+    let mut counts = ParityCounts::default();
+    let mut prev = &start;
+    for ((i, _, _), node) in rows.iter().zip(path.iter()) {
+        for arrow in notes[*i].arrows.iter_mut().filter(|a| a.is_note() && a.column < 4) {
+            arrow.foot = Some(if node.left_notes & (1 << arrow.column) != 0 { Foot::Left } else { Foot::Right });
+        }
+        let state = &node.state;
+        if is_crossed(state.left, state.right) && !is_crossed(prev.left, prev.right) {
+            counts.crossovers += 1;
+        }
+        counts.brackets += [state.left, state.right].iter().zip([1, 2]).filter(|(m, bit)| state.last & bit != 0 && m.count_ones() == 2).count() as i32;
+        // 片足で1つだけ踏んだ行が続いた場合
+        let single = |s: &FootState| match s.last {
+            1 if s.left.count_ones() == 1 => Some((1, s.left)),
+            2 if s.right.count_ones() == 1 => Some((2, s.right)),
+            _ => None,
+        };
+        if let (Some((prev_foot, prev_panel)), Some((foot, panel))) = (single(prev), single(state)) {
+            let other_until = if foot == 1 { prev.right_until } else { prev.left_until };
+            if prev_foot == foot && prev_panel == panel {
+                counts.jacks += 1;
+            } else if prev_foot == foot && other_until <= notes[*i].offset {
+                counts.doublesteps += 1;
+            } else if prev_foot != foot && prev_panel == panel {
+                if panel & UP_DOWN != 0 {
+                    counts.footswitches += 1;
+                } else {
+                    counts.sideswitches += 1;
+                }
+            }
+        }
+        prev = state;
+    }
+    counts
+}
+
+#[test]
+fn test_solve_parity() {
+    use crate::chart::{str_to_notes, ChartType};
+    use crate::gimmick::{Bpm, TimeSignature};
+    let parity = |bar: &str, bpm: f32| {
+        let bpms = [Bpm { offset: 0, bpm }];
+        let time_signatures = [TimeSignature { offset: 0, numerator: 4, denominator: 4 }];
+        let mut notes = str_to_notes(vec![bar], ChartType::DanceSingle, &bpms, &[], &time_signatures);
+        let counts = solve_parity(&mut notes);
+        let feet: String = notes.iter().flat_map(|d| d.arrows.iter()).map(|a| if a.foot == Some(Foot::Left) { 'L' } else { 'R' }).collect();
+        (feet, counts)
+    };
+    // 左下上右は左右交互に踏む
+    assert_eq!(parity("1000\n0100\n0010\n0001", 120.0), ("LRLR".to_string(), ParityCounts::default()));
+    // 左上右下左は右で左足が交差する
+    let (feet, counts) = parity("1000\n0010\n0001\n0100\n1000\n0000\n0000\n0000", 150.0);
+    assert_eq!(feet, "LRLRL");
+    assert_eq!(counts, ParityCounts { crossovers: 1, ..Default::default() });
+    // 遅い同じパネルは同じ足で、左と右の同時押しは両足で踏む
+    let (feet, counts) = parity("1000\n1000\n1001\n0000", 60.0);
+    assert_eq!(feet, "LLLR");
+    assert_eq!(counts, ParityCounts { jacks: 1, ..Default::default() });
+    // 2つの同時押しは両足、3つの同時押しは片足がbracket
+    assert_eq!(parity("1100\n0011\n0000\n0000", 120.0).0, "LRLR");
+    let (feet, counts) = parity("1101\n0000\n0000\n0000", 120.0);
+    assert!(feet.starts_with('L') && feet.ends_with('R'));
+    assert_eq!(counts, ParityCounts { brackets: 1, ..Default::default() });
+}
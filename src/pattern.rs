@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+
+use crate::arrow::{Division, Foot, NOTE_UNIT};
+use crate::chart::ChartType;
+
+// 譜面によく出る配置を見つける
+// 1拍より長く空いたところで配置は途切れるものとする
+// jack/jumpjack: 同じ行(単押し/同時押し)が続く
+// trill/drill: 2つの列を交互に踏む単押しが4〜7個/8個以上続く
+// gallop: 16分(12分)以下の間隔の2つのノーツの前後が2倍以上空いている
+// 以下はdance-singleのみ
+// staircase: 左下上右、または右上下左の単押し
+// swirl/spin: パッドの周りを同じ向きに回る単押しが3〜4個/5個以上(1周)続く
+// candle: 同じ足で上から下、下から上へ踏み替える(足運びの推定を使う)
+
+const GAP: i32 = NOTE_UNIT / 4;
+const GALLOP_INTERVAL: i32 = NOTE_UNIT / 12;
+const DRILL_LENGTH: usize = 8;
+const TRILL_LENGTH: usize = 4;
+const SPIN_LENGTH: usize = 5;
+const SWIRL_LENGTH: usize = 3;
+// パッドの周りの位置(左, 上, 右, 下の順)。列の順は左下上右
+const AROUND: [i32; 4] = [0, 3, 1, 2];
+const UP_DOWN: u64 = 0b0110;
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PatternKind {
+    Candle,
+    Drill,
+    Gallop,
+    Jack,
+    Jumpjack,
+    Staircase,
+    Swirl,
+    Spin,
+    Trill,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PatternOccurrence {
+    pub pattern: PatternKind,
+    // 最初と最後のノーツのoffset
+    pub start: i32,
+    pub end: i32,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct PatternCounts {
+    pub candles: i32,
+    pub drills: i32,
+    pub gallops: i32,
+    pub jacks: i32,
+    pub jumpjacks: i32,
+    pub staircases: i32,
+    pub swirls: i32,
+    pub spins: i32,
+    pub trills: i32,
+}
+
+impl PatternCounts {
+    pub fn from_occurrences(occurrences: &[PatternOccurrence]) -> PatternCounts {
+        let mut counts = PatternCounts::default();
+        for occurrence in occurrences {
+            let count = match occurrence.pattern {
+                PatternKind::Candle => &mut counts.candles,
+                PatternKind::Drill => &mut counts.drills,
+                PatternKind::Gallop => &mut counts.gallops,
+                PatternKind::Jack => &mut counts.jacks,
+                PatternKind::Jumpjack => &mut counts.jumpjacks,
+                PatternKind::Staircase => &mut counts.staircases,
+                PatternKind::Swirl => &mut counts.swirls,
+                PatternKind::Spin => &mut counts.spins,
+                PatternKind::Trill => &mut counts.trills,
+            };
+            *count += 1;
+        }
+        counts
+    }
+}
+
+// 踏むノーツのある行
+struct Row {
+    offset: i32,
+    // 列のビット
+    mask: u64,
+    // 単押しなら列
+    column: Option<usize>,
+    foot: Option<Foot>,
+}
+
+fn note_rows(notes: &[Division]) -> Vec<Row> {
+    notes
+        .iter()
+        .filter_map(|d| {
+            let arrows: Vec<_> = d.arrows.iter().filter(|a| a.is_note()).collect();
+            let mask = arrows.iter().fold(0u64, |mask, a| mask | (1 << a.column));
+            let column = (arrows.len() == 1).then(|| arrows[0].column);
+            (mask != 0).then(|| Row { offset: d.offset, mask, column, foot: arrows.first().and_then(|a| a.foot) })
+        })
+        .collect()
+}
+
+// 1拍以内の間隔で続く行の範囲
+fn runs(rows: &[Row]) -> Vec<&[Row]> {
+    rows.chunk_by(|a, b| b.offset - a.offset <= GAP).collect()
+}
+
+// 条件を満たす隣り合った組が続く範囲を、組の数がmin_pairs以上のものだけ(最初, 最後)で返す
+fn pair_runs(run: &[Row], min_pairs: usize, linked: impl Fn(&Row, &Row, Option<&Row>) -> bool) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    for i in 1..=run.len() {
+        let continues = i < run.len() && linked(&run[i - 1], &run[i], (i >= 2 && i - 2 >= start).then(|| &run[i - 2]));
+        if !continues {
+            if i - 1 - start >= min_pairs {
+                ranges.push((start, i - 1));
+            }
+            start = i;
+        }
+    }
+    ranges
+}
+
+fn occurrence(pattern: PatternKind, run: &[Row], (start, end): (usize, usize)) -> PatternOccurrence {
+    PatternOccurrence { pattern, start: run[start].offset, end: run[end].offset }
+}
+
+fn find_in_run(run: &[Row], dance_single: bool, occurrences: &mut Vec<PatternOccurrence>) {
+    for (start, end) in pair_runs(run, 1, |a, b, _| a.mask == b.mask) {
+        let pattern = if run[start].column.is_some() { PatternKind::Jack } else { PatternKind::Jumpjack };
+        occurrences.push(occurrence(pattern, run, (start, end)));
+    }
+    // 2つの列の交互
+    let alternating = |a: &Row, b: &Row, before: Option<&Row>| a.column.is_some() && b.column.is_some() && a.mask != b.mask && before.is_none_or(|r| r.mask == b.mask);
+    for (start, end) in pair_runs(run, TRILL_LENGTH - 1, alternating) {
+        let pattern = if end - start + 1 >= DRILL_LENGTH { PatternKind::Drill } else { PatternKind::Trill };
+        occurrences.push(occurrence(pattern, run, (start, end)));
+    }
+    if !dance_single {
+        return;
+    }
+    // 同じ向きに回る
+    for direction in [1, 3] {
+        let around = |a: &Row, b: &Row, _: Option<&Row>| match (a.column, b.column) {
+            (Some(a), Some(b)) => (AROUND[b] - AROUND[a]).rem_euclid(4) == direction,
+            _ => false,
+        };
+        for (start, end) in pair_runs(run, SWIRL_LENGTH - 1, around) {
+            let pattern = if end - start + 1 >= SPIN_LENGTH { PatternKind::Spin } else { PatternKind::Swirl };
+            occurrences.push(occurrence(pattern, run, (start, end)));
+        }
+    }
+    for (i, window) in run.windows(4).enumerate() {
+        let columns: Vec<Option<usize>> = window.iter().map(|r| r.column).collect();
+        if columns == [Some(0), Some(1), Some(2), Some(3)] || columns == [Some(3), Some(2), Some(1), Some(0)] {
+            occurrences.push(occurrence(PatternKind::Staircase, run, (i, i + 3)));
+        }
+    }
+    // 足が交互に動く中で、同じ足が上下を移動する
+    for (i, window) in run.windows(3).enumerate() {
+        let (a, b, c) = (&window[0], &window[1], &window[2]);
+        let single_foot = |r: &Row| r.column.and(r.foot);
+        let alternating = single_foot(a).is_some() && single_foot(b).is_some() && single_foot(a) != single_foot(b) && single_foot(a) == single_foot(c);
+        if alternating && a.mask & UP_DOWN != 0 && c.mask & UP_DOWN != 0 && a.mask != c.mask {
+            occurrences.push(occurrence(PatternKind::Candle, run, (i, i + 2)));
+        }
+    }
+}
+
+fn find_gallops(rows: &[Row], occurrences: &mut Vec<PatternOccurrence>) {
+    for i in 0..rows.len().saturating_sub(1) {
+        let interval = rows[i + 1].offset - rows[i].offset;
+        let before = (i > 0).then(|| rows[i].offset - rows[i - 1].offset);
+        let after = rows.get(i + 2).map(|r| r.offset - rows[i + 1].offset);
+        if interval <= GALLOP_INTERVAL && before.is_none_or(|b| b >= interval * 2) && after.is_none_or(|a| a >= interval * 2) {
+            occurrences.push(PatternOccurrence { pattern: PatternKind::Gallop, start: rows[i].offset, end: rows[i + 1].offset });
+        }
+    }
+}
+
+// 見つかった配置を始まりの順に返す
+pub fn find_patterns(notes: &[Division], chart_type: ChartType) -> Vec<PatternOccurrence> {
+    let rows = note_rows(notes);
+    let mut occurrences = Vec::new();
+    for run in runs(&rows) {
+        find_in_run(run, chart_type == ChartType::DanceSingle, &mut occurrences);
+    }
+    find_gallops(&rows, &mut occurrences);
+    occurrences.sort_by_key(|o| (o.start, o.end));
+    occurrences
+}
+
+#[test]
+fn test_find_patterns() {
+    use crate::chart::str_to_notes;
+    use crate::gimmick::{Bpm, TimeSignature};
+    use crate::parity::solve_parity;
+    let patterns = |bars: Vec<&str>| {
+        let bpms = [Bpm { offset: 0, bpm: 150.0 }];
+        let time_signatures = [TimeSignature { offset: 0, numerator: 4, denominator: 4 }];
+        let mut notes = str_to_notes(bars, ChartType::DanceSingle, &bpms, &[], &time_signatures);
+        solve_parity(&mut notes);
+        find_patterns(&notes, ChartType::DanceSingle).iter().map(|o| (o.pattern, o.start / 12, o.end / 12)).collect::<Vec<_>>()
+    };
+    let empty = "0000\n".repeat(11);
+    // 16分で 左下上右 左左 右左右左右左右左
+    let bar = "1000\n0100\n0010\n0001\n1000\n1000\n0001\n1000\n0001\n1000\n0001\n1000\n0001\n1000\n0000\n0000";
+    assert_eq!(patterns(vec![bar]), vec![(PatternKind::Staircase, 0, 3), (PatternKind::Jack, 4, 5), (PatternKind::Drill, 5, 13)]);
+    // 左上右下左は1周回り、途中で右足が上から下に動く。上左下も同じ足が上から下に動き、左回りでもある
+    let spin = format!("1000\n0010\n0001\n0100\n1000\n{}", empty);
+    let candle = format!("0010\n1000\n0100\n0000\n0000\n{}", empty);
+    assert_eq!(
+        patterns(vec![&spin, &candle]),
+        vec![(PatternKind::Spin, 0, 4), (PatternKind::Candle, 1, 3), (PatternKind::Swirl, 16, 18), (PatternKind::Candle, 16, 18)]
+    );
+    // 前後が空いた16分の2つ
+    let first = format!("1000\n0100\n0000\n0000\n0000\n{}", empty);
+    let second = format!("0010\n0001\n0000\n0000\n0000\n{}", empty);
+    assert_eq!(patterns(vec![&first, &second]), vec![(PatternKind::Gallop, 0, 1), (PatternKind::Gallop, 16, 17)]);
+}
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::arrow::{Division, NOTE_UNIT};
+use crate::gimmick::Bpm;
+
+// ハイスピードの選び方
+// 最初のノーツから最後のノーツ(freezeの終点)までで、一番長い時間(停止は除く)使われるBPMと、一番多くのノーツがあるBPMを求める
+// xModは時間で決めたBPMで見た速さ(BPM×倍率)が目標に一番近いものを選ぶ
+// mmod/cmodはそのxModと同じ速さになる値(MModは最大BPMを基準にする)
+
+pub const DEFAULT_READ_SPEED: f32 = 500.0;
+
+// 0.25刻みで4倍まで、その後は0.5刻みで8倍まで
+fn xmod_steps() -> Vec<f32> {
+    (1..=16).map(|i| i as f32 * 0.25).chain((9..=16).map(|i| i as f32 * 0.5)).collect()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct XModSpeed {
+    pub xmod: f32,
+    // 時間で決めたBPMで見た速さ
+    pub read_speed: f32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ScrollSpeed {
+    // 時間で決めたBPM
+    pub dominant_bpm: f32,
+    // ノーツ数で決めたBPM
+    pub dominant_bpm_by_notes: f32,
+    // ノーツのある区間のBPMの最小と最大
+    pub min_bpm: f32,
+    pub max_bpm: f32,
+    pub xmods: Vec<XModSpeed>,
+    pub target_read_speed: f32,
+    pub recommended_xmod: f32,
+    pub mmod: i32,
+    pub cmod: i32,
+}
+
+fn bpm_at(bpms: &[Bpm], offset: i32) -> f32 {
+    bpms.iter().rev().find(|b| b.offset <= offset).unwrap_or(&bpms[0]).bpm
+}
+
+// 重みが一番大きいBPM(同じなら遅い方)。BPMは小数点以下3桁で同じものとみなす
+fn heaviest(weights: &BTreeMap<i64, f32>) -> Option<f32> {
+    weights.iter().fold(None, |best: Option<(i64, f32)>, (bpm, weight)| match best {
+        Some((_, w)) if w >= *weight => best,
+        _ => Some((*bpm, *weight)),
+    })
+    .map(|(bpm, _)| bpm as f32 / 1000.0)
+}
+
+fn bpm_key(bpm: f32) -> i64 {
+    (bpm * 1000.0).round() as i64
+}
+
+pub fn get_scroll_speed(notes: &[Division], bpms: &[Bpm], target_read_speed: f32) -> ScrollSpeed {
+    let start = notes.first().map_or(0, |d| d.offset);
+    let end = notes.iter().flat_map(|d| std::iter::once(d.offset).chain(d.arrows.iter().filter(|a| a.is_hold()).map(|a| a.end))).max().unwrap_or(0);
+
+    let mut by_time: BTreeMap<i64, f32> = BTreeMap::new();
+    let mut min_bpm = bpm_at(bpms, start);
+    let mut max_bpm = min_bpm;
+    for (i, bpm) in bpms.iter().enumerate() {
+        let from = bpm.offset.max(start);
+        let to = bpms.get(i + 1).map_or(end, |next| next.offset.min(end));
+        if from < to {
+            min_bpm = min_bpm.min(bpm.bpm);
+            max_bpm = max_bpm.max(bpm.bpm);
+            let seconds = (to - from) as f32 / (NOTE_UNIT / 4) as f32 * 60.0 / bpm.bpm;
+            *by_time.entry(bpm_key(bpm.bpm)).or_default() += seconds;
+        }
+    }
+    let mut by_notes: BTreeMap<i64, f32> = BTreeMap::new();
+    for division in notes.iter().filter(|d| d.arrows.iter().any(|a| a.is_note())) {
+        *by_notes.entry(bpm_key(bpm_at(bpms, division.offset))).or_default() += 1.0;
+    }
+    let dominant_bpm = heaviest(&by_time).unwrap_or(min_bpm);
+    let dominant_bpm_by_notes = heaviest(&by_notes).unwrap_or(dominant_bpm);
+
+    let xmods: Vec<XModSpeed> = xmod_steps().into_iter().map(|xmod| XModSpeed { xmod, read_speed: xmod * dominant_bpm }).collect();
+    // 同じだけ離れていたら遅い方
+    let recommended_xmod = xmods
+        .iter()
+        .fold(None, |best: Option<&XModSpeed>, x| match best {
+            Some(b) if (b.read_speed - target_read_speed).abs() <= (x.read_speed - target_read_speed).abs() => best,
+            _ => Some(x),
+        })
+        .map_or(1.0, |x| x.xmod);
+    ScrollSpeed {
+        dominant_bpm,
+        dominant_bpm_by_notes,
+        min_bpm,
+        max_bpm,
+        xmods,
+        target_read_speed,
+        recommended_xmod,
+        mmod: (recommended_xmod * max_bpm).round() as i32,
+        cmod: (recommended_xmod * dominant_bpm).round() as i32,
+    }
+}
+
+#[test]
+fn test_get_scroll_speed() {
+    use crate::chart::{ChartType, str_to_notes};
+    use crate::gimmick::TimeSignature;
+    // 150で3小節の4分、300で1小節の16分
+    let bpms = [Bpm { offset: 0, bpm: 150.0 }, Bpm { offset: NOTE_UNIT * 3, bpm: 300.0 }];
+    let time_signatures = [TimeSignature { offset: 0, numerator: 4, denominator: 4 }];
+    let quarters = "1000\n0100\n0010\n0001";
+    let sixteenths = "1000\n0100\n0010\n0001\n".repeat(4);
+    let notes = str_to_notes(vec![quarters, quarters, quarters, &sixteenths, "1000\n0000\n0000\n0000"], ChartType::DanceSingle, &bpms, &[], &time_signatures);
+    let speed = get_scroll_speed(&notes, &bpms, 450.0);
+    assert_eq!(speed.dominant_bpm, 150.0);
+    assert_eq!(speed.dominant_bpm_by_notes, 300.0);
+    assert_eq!((speed.min_bpm, speed.max_bpm), (150.0, 300.0));
+    assert_eq!(speed.xmods.len(), 24);
+    assert_eq!(speed.recommended_xmod, 3.0);
+    assert_eq!((speed.mmod, speed.cmod), (900, 450));
+    // 4.5倍なら675
+    assert_eq!(speed.xmods.iter().find(|x| x.xmod == 4.5).unwrap().read_speed, 675.0);
+}
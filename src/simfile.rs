@@ -0,0 +1,301 @@
+use std::fs;
+
+// .sm/.ssc をタグ単位で読み書きするためのモデル
+// 譜面の解析はchart.rsで行い、ここでは値を文字列のまま保持する
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    Sm,
+    Ssc,
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Option<Format> {
+        if path.ends_with(".sm") {
+            Some(Format::Sm)
+        } else if path.ends_with(".ssc") {
+            Some(Format::Ssc)
+        } else {
+            None
+        }
+    }
+}
+
+// .smのNOTESに含まれる項目。.sscではそれぞれ独立したタグになっている
+const SM_NOTES_FIELDS: [&str; 6] = ["STEPSTYPE", "DESCRIPTION", "DIFFICULTY", "METER", "RADARVALUES", "NOTES"];
+
+// .sscにしか存在しないタイミング関連のタグと、その既定値
+const SSC_ONLY_TIMING_TAGS: [(&str, &str); 9] = [
+    ("WARPS", ""),
+    ("DELAYS", ""),
+    ("FAKES", ""),
+    ("SCROLLS", "0=1"),
+    ("SPEEDS", "0=1=0=0"),
+    ("LABELS", "0=Song Start"),
+    ("TICKCOUNTS", "0=4"),
+    ("COMBOS", "0=1"),
+    ("TIMESIGNATURES", "0=4=4"),
+];
+
+// .sscで譜面ごとに持てるタイミング関連のタグ
+const CHART_TIMING_TAGS: [&str; 3] = ["OFFSET", "BPMS", "STOPS"];
+
+// .smのNOTESではDESCRIPTIONの欄に入れる.sscの譜面ごとの文字列(先にあるものを優先する)
+const CHART_TEXT_TAGS: [&str; 3] = ["DESCRIPTION", "CHARTNAME", "CREDIT"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tag {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimfileChart {
+    pub tags: Vec<Tag>,
+}
+
+impl SimfileChart {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find(|t| t.key == key).map(|t| t.value.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Simfile {
+    pub header: Vec<Tag>,
+    pub charts: Vec<SimfileChart>,
+}
+
+pub fn remove_comments(contents: &str) -> String {
+    contents
+        .split('\n')
+        .map(|line| match line.find("//") {
+            Some(i) => &line[..i],
+            None => line,
+        })
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+// "#KEY:VALUE;" の列に分解する
+pub fn split_tags(contents: &str) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    let mut rest = contents;
+    while let Some(start) = rest.find('#') {
+        rest = &rest[start + 1..];
+        let end = rest.find(';').unwrap_or(rest.len());
+        let statement = &rest[..end];
+        rest = &rest[(end + 1).min(rest.len())..];
+        if let Some(colon) = statement.find(':') {
+            tags.push(Tag {
+                key: statement[..colon].trim().to_uppercase(),
+                value: statement[colon + 1..].trim().to_string(),
+            });
+        }
+    }
+    tags
+}
+
+fn sm_notes_to_chart(value: &str) -> SimfileChart {
+    let fields: Vec<&str> = value.splitn(SM_NOTES_FIELDS.len(), ':').collect();
+    SimfileChart {
+        tags: SM_NOTES_FIELDS
+            .iter()
+            .zip(fields.iter().chain(std::iter::repeat(&"")))
+            .map(|(key, value)| Tag {
+                key: key.to_string(),
+                value: value.trim().to_string(),
+            })
+            .collect(),
+    }
+}
+
+impl Simfile {
+    pub fn load(filepath: &str) -> Simfile {
+        let contents = fs::read_to_string(filepath).expect("file open error");
+        let format = Format::from_path(filepath).expect("unsupported file format");
+        Simfile::parse(&contents, format)
+    }
+
+    pub fn parse(contents: &str, format: Format) -> Simfile {
+        let mut header = Vec::new();
+        let mut charts: Vec<SimfileChart> = Vec::new();
+        for tag in split_tags(&remove_comments(contents)) {
+            match format {
+                Format::Sm => {
+                    if tag.key == "NOTES" {
+                        charts.push(sm_notes_to_chart(&tag.value));
+                    } else if tag.key == "FREEZES" {
+                        // 古い.smではSTOPSをFREEZESと書く
+                        header.push(Tag { key: "STOPS".to_string(), value: tag.value });
+                    } else {
+                        header.push(tag);
+                    }
+                }
+                Format::Ssc => {
+                    if tag.key == "NOTEDATA" {
+                        charts.push(SimfileChart { tags: Vec::new() });
+                    } else if let Some(chart) = charts.last_mut() {
+                        chart.tags.push(tag);
+                    } else {
+                        header.push(tag);
+                    }
+                }
+            }
+        }
+        Simfile { header, charts }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.header.iter().find(|t| t.key == key).map(|t| t.value.as_str())
+    }
+
+    pub fn to_ssc(&self) -> String {
+        let mut out = String::new();
+        if self.get("VERSION").is_none() {
+            out.push_str("#VERSION:0.83;\n");
+        }
+        for tag in &self.header {
+            push_tag(&mut out, tag);
+        }
+        for chart in &self.charts {
+            out.push_str(&format!(
+                "//---------------{} - {}----------------\n",
+                chart.get("STEPSTYPE").unwrap_or(""),
+                chart.get("DESCRIPTION").unwrap_or("")
+            ));
+            out.push_str("#NOTEDATA:;\n");
+            for tag in &chart.tags {
+                push_tag(&mut out, tag);
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    // .smで表現できない情報は捨てて、その内容を警告として返す
+    pub fn to_sm(&self) -> (String, Vec<String>) {
+        let mut out = String::new();
+        let mut warnings = Vec::new();
+        for tag in &self.header {
+            if tag.key == "VERSION" {
+                continue;
+            }
+            if let Some((_, default)) = SSC_ONLY_TIMING_TAGS.iter().find(|(key, _)| *key == tag.key) {
+                if !same_segments(&tag.value, default) {
+                    warnings.push(format!("#{} cannot be represented in .sm and was dropped", tag.key));
+                }
+                continue;
+            }
+            push_tag(&mut out, tag);
+        }
+        for chart in &self.charts {
+            let name = format!(
+                "{} {}",
+                chart.get("STEPSTYPE").unwrap_or(""),
+                chart.get("DIFFICULTY").unwrap_or("")
+            );
+            for tag in &chart.tags {
+                if SM_NOTES_FIELDS.contains(&tag.key.as_str()) {
+                    continue;
+                }
+                let expected = if CHART_TIMING_TAGS.contains(&tag.key.as_str()) {
+                    self.get(&tag.key).unwrap_or(if tag.key == "OFFSET" { "0" } else { "" })
+                } else if tag.key == "DISPLAYBPM" && !tag.value.is_empty() {
+                    self.get("DISPLAYBPM").unwrap_or("")
+                } else if let Some((_, default)) = SSC_ONLY_TIMING_TAGS.iter().find(|(key, _)| *key == tag.key) {
+                    default
+                } else {
+                    continue;
+                };
+                if !same_segments(&tag.value, expected) {
+                    warnings.push(format!(
+                        "{}: per-chart #{} cannot be represented in .sm and was dropped",
+                        name, tag.key
+                    ));
+                }
+            }
+            let texts: Vec<(&str, &str)> = CHART_TEXT_TAGS
+                .iter()
+                .filter_map(|key| chart.get(key).filter(|v| !v.trim().is_empty()).map(|v| (*key, v)))
+                .collect();
+            let description = texts.first().map_or("", |(_, v)| v);
+            for (key, value) in texts.iter().skip(1).filter(|(_, v)| *v != description) {
+                warnings.push(format!("{}: #{} \"{}\" cannot be represented in .sm and was dropped", name, key, value));
+            }
+            out.push_str("\n//---------------");
+            out.push_str(&name);
+            out.push_str("----------------\n#NOTES:\n");
+            for (key, value) in SM_NOTES_FIELDS.iter().take(5).zip([
+                chart.get("STEPSTYPE").unwrap_or(""),
+                description,
+                chart.get("DIFFICULTY").unwrap_or(""),
+                chart.get("METER").unwrap_or(""),
+                chart.get("RADARVALUES").unwrap_or(""),
+            ]) {
+                if value.is_empty() && *key != "DESCRIPTION" && *key != "RADARVALUES" {
+                    warnings.push(format!("{}: #{} is empty", name, key));
+                }
+                out.push_str(&format!("     {}:\n", value));
+            }
+            out.push_str(chart.get("NOTES").unwrap_or(""));
+            out.push_str("\n;\n");
+        }
+        (out, warnings)
+    }
+}
+
+fn push_tag(out: &mut String, tag: &Tag) {
+    if tag.value.contains('\n') {
+        out.push_str(&format!("#{}:\n{}\n;\n", tag.key, tag.value));
+    } else {
+        out.push_str(&format!("#{}:{};\n", tag.key, tag.value));
+    }
+}
+
+// "0.000=150.000,64.000=300.000" のような区間リストを数値として比較する
+fn same_segments(a: &str, b: &str) -> bool {
+    let parse = |s: &str| -> Vec<Vec<String>> {
+        s.split(',')
+            .map(|seg| seg.trim())
+            .filter(|seg| !seg.is_empty())
+            .map(|seg| seg.split('=').map(|v| v.trim().to_string()).collect())
+            .collect()
+    };
+    let (a, b) = (parse(a), parse(b));
+    a.len() == b.len()
+        && a.iter().zip(b.iter()).all(|(x, y)| {
+            x.len() == y.len()
+                && x.iter().zip(y.iter()).all(|(v, w)| match (v.parse::<f32>(), w.parse::<f32>()) {
+                    (Ok(v), Ok(w)) => (v - w).abs() < 0.001,
+                    _ => v == w,
+                })
+        })
+}
+
+#[test]
+fn test_sm_ssc_round_trip() {
+    let sm = "#TITLE:test;\n#BPMS:0.000=150.000;\n#NOTES:\n     dance-single:\n     author:\n     Hard:\n     9:\n     0,0,0,0,0:\n1000\n0100\n0010\n0001\n,\n0000\n;\n";
+    let simfile = Simfile::parse(sm, Format::Sm);
+    let ssc = Simfile::parse(&simfile.to_ssc(), Format::Ssc);
+    assert_eq!(ssc.get("VERSION"), Some("0.83"));
+    assert_eq!(ssc.charts[0].get("DIFFICULTY"), Some("Hard"));
+    let (sm_again, warnings) = ssc.to_sm();
+    assert!(warnings.is_empty());
+    assert_eq!(Simfile::parse(&sm_again, Format::Sm), simfile);
+
+    let mut per_chart = ssc.clone();
+    per_chart.charts[0].tags.push(Tag { key: "BPMS".to_string(), value: "0.000=200.000".to_string() });
+    per_chart.charts[0].tags.push(Tag { key: "WARPS".to_string(), value: "4.000=1.000".to_string() });
+    assert_eq!(per_chart.to_sm().1.len(), 2);
+
+    // 譜面ごとの文字列は1つだけDESCRIPTIONに入れ、入らなかったものと譜面ごとのDISPLAYBPMは警告する
+    let mut texts = ssc.clone();
+    texts.charts[0].tags.retain(|t| t.key != "DESCRIPTION");
+    for (key, value) in [("CHARTNAME", "Extra"), ("CREDIT", "someone"), ("DISPLAYBPM", "300")] {
+        texts.charts[0].tags.push(Tag { key: key.to_string(), value: value.to_string() });
+    }
+    let (sm_texts, warnings) = texts.to_sm();
+    assert_eq!(Simfile::parse(&sm_texts, Format::Sm).charts[0].get("DESCRIPTION"), Some("Extra"));
+    assert_eq!(warnings.len(), 2);
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::arrow::{ArrowType, Division};
+
+// StepManiaのRadarCategoryと同じ数え方
+// taps: ノーツのある行数, jumps: 2つ以上同時の行数(handsも含む)
+// hands: 押しっぱなしのfreeze/rollも含めて3つ以上になる行数(NoteDataUtil::GetNumHands)
+// quads: 同時に踏む矢印が4つ以上の行数。押しっぱなしのfreeze/rollは含めない(NoteDataUtil::GetNumQuads)
+// holds/rolls/mines/lifts: それぞれの個数
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct StepCounts {
+    pub taps: i32,
+    pub jumps: i32,
+    pub hands: i32,
+    pub quads: i32,
+    pub holds: i32,
+    pub rolls: i32,
+    pub mines: i32,
+    pub lifts: i32,
+}
+
+// 最大コンボの数え方
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ComboRule {
+    // DDR X以降: 同時押しは1コンボ、freeze/rollは離した時のOKでも1コンボ
+    Ddr,
+    // DDR SuperNOVA2まで: 同時押しは1コンボ、freezeは数えない
+    DdrClassic,
+    // 矢印1つごとに1コンボ(同時押しは矢印の数だけ)
+    PerNote,
+}
+
+impl FromStr for ComboRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ddr" => Ok(ComboRule::Ddr),
+            "ddr-classic" => Ok(ComboRule::DdrClassic),
+            "per-note" => Ok(ComboRule::PerNote),
+            _ => Err(format!("{} is not combo rule", s)),
+        }
+    }
+}
+
+pub fn count_steps(notes: &[Division]) -> StepCounts {
+    let mut counts = StepCounts::default();
+    // 押しっぱなしのfreeze/rollの(始点, 終点)
+    let holds: Vec<(i32, i32)> = notes
+        .iter()
+        .flat_map(|d| d.arrows.iter().filter(|a| a.is_hold()).map(move |a| (d.offset, a.end)))
+        .collect();
+    for division in notes {
+        let pressed = division.arrows.iter().filter(|a| a.is_note()).count();
+        for arrow in &division.arrows {
+            match arrow.arrow_type {
+                ArrowType::Freeze => counts.holds += 1,
+                ArrowType::Roll => counts.rolls += 1,
+                ArrowType::Mine => counts.mines += 1,
+                ArrowType::Lift => counts.lifts += 1,
+                _ => {}
+            }
+        }
+        if pressed == 0 {
+            continue;
+        }
+        let held = holds.iter().filter(|(start, end)| *start < division.offset && division.offset <= *end).count();
+        counts.taps += 1;
+        if pressed >= 2 {
+            counts.jumps += 1;
+        }
+        if pressed + held >= 3 {
+            counts.hands += 1;
+        }
+        if pressed >= 4 {
+            counts.quads += 1;
+        }
+    }
+    counts
+}
+
+pub fn max_combo(notes: &[Division], rule: ComboRule) -> i32 {
+    let rows = notes.iter().filter(|d| d.arrows.iter().any(|a| a.is_note())).count();
+    let arrows = notes.iter().flat_map(|d| d.arrows.iter()).filter(|a| a.is_note()).count();
+    let holds = notes.iter().flat_map(|d| d.arrows.iter()).filter(|a| a.is_hold()).count();
+    (match rule {
+        ComboRule::Ddr => rows + holds,
+        ComboRule::DdrClassic => rows,
+        ComboRule::PerNote => arrows,
+    }) as i32
+}
+
+#[test]
+fn test_count_steps() {
+    use crate::chart::{ChartType, str_to_notes};
+    use crate::gimmick::Bpm;
+    // freezeを押しながらの同時押しはhandsだがquadsではない、地雷だけの行は数えない
+    let bar = "2000\n0111\n3000\nM000\n1111\n0401\n0300\n00L0\n";
+    let notes = str_to_notes(vec![bar], ChartType::DanceSingle, &[Bpm { offset: 0, bpm: 120.0 }], &[], &[]);
+    let counts = count_steps(&notes);
+    assert_eq!(counts, StepCounts { taps: 5, jumps: 3, hands: 2, quads: 1, holds: 1, rolls: 1, mines: 1, lifts: 1 });
+    assert_eq!(max_combo(&notes, ComboRule::Ddr), 7);
+    assert_eq!(max_combo(&notes, ComboRule::DdrClassic), 5);
+    assert_eq!(max_combo(&notes, ComboRule::PerNote), 11);
+}
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+
+use crate::arrow::{Division, NOTE_UNIT};
+use crate::gimmick::{TimeSignature, measure_ranges};
+
+// ITGでよく使われる小節ごとの密度による譜面の分類
+// 1拍あたりのノーツ数で判定するので、4/4以外の小節でも同じ基準になる
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MeasureType {
+    Break,
+    #[serde(rename = "16th")]
+    Sixteenth,
+    #[serde(rename = "20th")]
+    Twentieth,
+    #[serde(rename = "24th")]
+    TwentyFourth,
+    #[serde(rename = "32nd")]
+    ThirtySecond,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StreamInfo {
+    pub notes_per_measure: Vec<i32>,
+    pub measure_types: Vec<MeasureType>,
+    // 例: "32 (4) 16 - 8 / 64*"
+    pub breakdown: String,
+    pub stream_measures: i32,
+    // 最初のstreamから最後のstreamまでの小節のうちstreamの割合(%)
+    pub stream_density: f32,
+}
+
+fn classify(notes: i32, beats: f32) -> MeasureType {
+    let notes_per_beat = notes as f32 / beats;
+    if notes_per_beat >= 8.0 {
+        MeasureType::ThirtySecond
+    } else if notes_per_beat >= 6.0 {
+        MeasureType::TwentyFourth
+    } else if notes_per_beat >= 5.0 {
+        MeasureType::Twentieth
+    } else if notes_per_beat >= 4.0 {
+        MeasureType::Sixteenth
+    } else {
+        MeasureType::Break
+    }
+}
+
+// streamの連続は小節数、休みは1小節なら"-"、2,3小節なら"/"、それ以上は"(小節数)"
+// 16分より細かい小節を含むstreamには"*"を付ける
+// 最初のstreamより前と最後のstreamより後の休みは書かない
+fn breakdown(measure_types: &[MeasureType]) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < measure_types.len() {
+        let is_break = measure_types[i] == MeasureType::Break;
+        let len = measure_types[i..].iter().take_while(|t| (**t == MeasureType::Break) == is_break).count();
+        let run = &measure_types[i..i + len];
+        if !is_break {
+            let dense = run.iter().any(|t| *t != MeasureType::Sixteenth);
+            parts.push(format!("{}{}", len, if dense { "*" } else { "" }));
+        } else if !parts.is_empty() && i + len < measure_types.len() {
+            parts.push(match len {
+                1 => "-".to_string(),
+                2 | 3 => "/".to_string(),
+                _ => format!("({})", len),
+            });
+        }
+        i += len;
+    }
+    parts.join(" ")
+}
+
+pub fn get_stream_info(notes: &[Division], time_signatures: &[TimeSignature]) -> StreamInfo {
+    let last = notes.last().map_or(0, |d| d.offset);
+    let ranges = measure_ranges(time_signatures, last);
+    let mut notes_per_measure = vec![0; ranges.len()];
+    for division in notes {
+        // 地雷だけの行は数えない
+        if division.arrows.iter().any(|a| a.is_note()) {
+            notes_per_measure[division.measure as usize] += 1;
+        }
+    }
+    let measure_types: Vec<MeasureType> = ranges
+        .iter()
+        .zip(notes_per_measure.iter())
+        .map(|((start, end), notes)| classify(*notes, (end - start) as f32 / (NOTE_UNIT / 4) as f32))
+        .collect();
+
+    let stream_measures = measure_types.iter().filter(|t| **t != MeasureType::Break).count() as i32;
+    let first = measure_types.iter().position(|t| *t != MeasureType::Break);
+    let last = measure_types.iter().rposition(|t| *t != MeasureType::Break);
+    let stream_density = match (first, last) {
+        (Some(first), Some(last)) => stream_measures as f32 * 100.0 / (last - first + 1) as f32,
+        _ => 0.0,
+    };
+    StreamInfo {
+        breakdown: breakdown(&measure_types),
+        notes_per_measure,
+        measure_types,
+        stream_measures,
+        stream_density,
+    }
+}
+
+#[test]
+fn test_breakdown() {
+    use MeasureType::*;
+    let mut types = vec![Break, Break];
+    types.extend([Sixteenth; 32]);
+    types.extend([Break; 4]);
+    types.extend([Sixteenth; 16]);
+    types.push(Break);
+    types.extend([Sixteenth; 8]);
+    types.extend([Break; 2]);
+    types.extend([Sixteenth; 60]);
+    types.extend([ThirtySecond; 4]);
+    types.push(Break);
+    assert_eq!(breakdown(&types), "32 (4) 16 - 8 / 64*");
+}
@@ -0,0 +1,485 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::arrow::{Arrow, ArrowType, Direction, Division, NOTE_UNIT, ofs_to_color};
+use crate::chart::{Chart, ChartType, make_chart, offset_to_time};
+use crate::gimmick::{Bpm, Stop, TimeSignature, measure_of, measure_starts};
+use crate::groove_radar::{MusicLength, RadarEra};
+
+// 練習用に譜面を変える(StepManiaのturn/remove/insertのmodifier)
+// 変えたノーツから譜面を作り直すので、グルーブレーダーや足運びなども計算し直される
+// turn:
+//   mirror: 列を左右反対にする
+//   left/right: パネルを左/右に90度回す(danceのみ)
+//   shuffle: 列を入れ替える。supershuffle: 行ごとに列を入れ替える(freeze中の列は使わない)
+// remove:
+//   nojumps: 同時押しを単押しにする。nohands: freeze中を含めて3つ以上の同時押しを2つにする
+//   noholds: freeze/rollをタップにする。nomines: 地雷を消す。little: 拍の頭以外を消す
+// insert(freeze中には足さない):
+//   wide: 前後が半拍以上空いた拍の頭の単押しを、反対の列との同時押しにする
+//   big/quick: 1拍/半拍空いた単押しの間に8分/16分を足す
+//   skippy: 1拍空いた単押しの間の、後ろのノーツの16分前に、前のノーツと同じ列のノーツを足す
+//   echo: 後ろが1拍以上空いたノーツの8分後に同じ列のノーツを足す
+//   stomp: 単押しを反対の列との同時押しにする
+//   planted: タップを次のノーツまでのfreezeにする(freezeは同時に1つまで)
+//   twister: 左右の端の列と上下の列を交互に踏むところで、端の列の3つ目を反対側にして交差させる(danceのみ)
+
+const BEAT: i32 = NOTE_UNIT / 4;
+// パネルを左に回したときの移動先(左下上右の順)
+const LEFT_TURN: [usize; 4] = [1, 3, 0, 2];
+const RIGHT_TURN: [usize; 4] = [2, 0, 3, 1];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transform {
+    Mirror,
+    Left,
+    Right,
+    Shuffle(u64),
+    SuperShuffle(u64),
+    NoJumps,
+    NoHands,
+    NoHolds,
+    NoMines,
+    Little,
+    Wide,
+    Big,
+    Quick,
+    Skippy,
+    Echo,
+    Stomp,
+    Planted,
+    Twister,
+}
+
+// shuffleとsupershuffleは"shuffle:42"のようにシードを指定できる(省略したら0)
+impl FromStr for Transform {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, seed) = match s.split_once(':') {
+            Some((name, seed)) => (name, Some(seed.parse::<u64>().map_err(|e| format!("{}: {}", s, e))?)),
+            None => (s, None),
+        };
+        let transform = match name {
+            "shuffle" => return Ok(Transform::Shuffle(seed.unwrap_or(0))),
+            "supershuffle" => return Ok(Transform::SuperShuffle(seed.unwrap_or(0))),
+            "mirror" => Transform::Mirror,
+            "left" => Transform::Left,
+            "right" => Transform::Right,
+            "nojumps" => Transform::NoJumps,
+            "nohands" => Transform::NoHands,
+            "noholds" => Transform::NoHolds,
+            "nomines" => Transform::NoMines,
+            "little" => Transform::Little,
+            "wide" => Transform::Wide,
+            "big" => Transform::Big,
+            "quick" => Transform::Quick,
+            "skippy" => Transform::Skippy,
+            "echo" => Transform::Echo,
+            "stomp" => Transform::Stomp,
+            "planted" => Transform::Planted,
+            "twister" => Transform::Twister,
+            _ => return Err(format!("{} is not a transform", s)),
+        };
+        match seed {
+            Some(_) => Err(format!("{} does not take a seed", name)),
+            None => Ok(transform),
+        }
+    }
+}
+
+// FromStrで読める書き方に戻す
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transform::Shuffle(seed) => write!(f, "shuffle:{}", seed),
+            Transform::SuperShuffle(seed) => write!(f, "supershuffle:{}", seed),
+            _ => write!(f, "{}", format!("{:?}", self).to_lowercase()),
+        }
+    }
+}
+
+// シードから同じ並びを返す乱数(splitmix64)
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, (self.next() % (i as u64 + 1)) as usize);
+        }
+    }
+}
+
+// freeze/rollの(列, 始点, 終点)
+type HoldSpan = (usize, i32, i32);
+
+// columnがNoneならどれかの列
+fn is_held(spans: &[HoldSpan], offset: i32, column: Option<usize>) -> bool {
+    spans.iter().any(|(c, start, end)| *start < offset && offset <= *end && column.is_none_or(|column| column == *c))
+}
+
+// 踏むノーツが1つだけのタップの行なら列
+fn single(arrows: &[Arrow]) -> Option<usize> {
+    let mut notes = arrows.iter().filter(|a| a.is_note());
+    match (notes.next(), notes.next()) {
+        (Some(a), None) if a.arrow_type == ArrowType::Normal => Some(a.column),
+        _ => None,
+    }
+}
+
+// 変える途中のノーツ。offsetごとの矢印
+struct Notes {
+    rows: BTreeMap<i32, Vec<Arrow>>,
+    directions: Vec<Direction>,
+}
+
+impl Notes {
+    fn tap(&self, column: usize) -> Arrow {
+        Arrow { direction: self.directions[column], column, arrow_type: ArrowType::Normal, end: 0, end_time: 0.0, foot: None }
+    }
+    fn columns(&self) -> usize {
+        self.directions.len()
+    }
+    fn hold_spans(&self) -> Vec<HoldSpan> {
+        self.rows.iter().flat_map(|(offset, arrows)| arrows.iter().filter(|a| a.is_hold()).map(|a| (a.column, *offset, a.end))).collect()
+    }
+    // 踏むノーツのある行の(offset, 単押しなら列)
+    fn note_rows(&self) -> Vec<(i32, Option<usize>)> {
+        self.rows.iter().filter(|(_, arrows)| arrows.iter().any(|a| a.is_note())).map(|(offset, arrows)| (*offset, single(arrows))).collect()
+    }
+    fn remap(&mut self, map: impl Fn(usize) -> usize) {
+        for arrow in self.rows.values_mut().flatten() {
+            arrow.column = map(arrow.column);
+        }
+    }
+    fn mirror_column(&self, column: usize) -> usize {
+        self.columns() - 1 - column
+    }
+
+    fn super_shuffle(&mut self, rng: &mut Rng) {
+        let columns = self.columns();
+        let mut held_until = vec![i32::MIN; columns];
+        for (offset, arrows) in self.rows.iter_mut() {
+            let mut free: Vec<usize> = (0..columns).filter(|c| held_until[*c] < *offset).collect();
+            rng.shuffle(&mut free);
+            arrows.retain_mut(|arrow| match free.pop() {
+                Some(column) => {
+                    arrow.column = column;
+                    if arrow.is_hold() {
+                        held_until[column] = arrow.end;
+                    }
+                    true
+                }
+                None => false,
+            });
+        }
+    }
+
+    fn no_hands(&mut self) {
+        let mut held_until = vec![i32::MIN; self.columns()];
+        for (offset, arrows) in self.rows.iter_mut() {
+            let mut pressed = held_until.iter().filter(|end| **end > *offset).count();
+            arrows.retain(|arrow| {
+                if !arrow.is_note() {
+                    return true;
+                }
+                pressed += 1;
+                pressed <= 2
+            });
+            for arrow in arrows.iter().filter(|a| a.is_hold()) {
+                held_until[arrow.column] = arrow.end;
+            }
+        }
+    }
+
+    // 単押しを反対の列との同時押しにする。beat_onlyなら前後が半拍以上空いた拍の頭だけ
+    fn add_mirror_jumps(&mut self, beat_only: bool) {
+        let spans = self.hold_spans();
+        let rows = self.note_rows();
+        for (i, (offset, column)) in rows.iter().enumerate() {
+            let Some(column) = *column else { continue };
+            let mirror = self.mirror_column(column);
+            let isolated = (i == 0 || offset - rows[i - 1].0 >= BEAT / 2) && rows.get(i + 1).is_none_or(|next| next.0 - offset >= BEAT / 2);
+            if mirror == column || is_held(&spans, *offset, None) || (beat_only && (offset % BEAT != 0 || !isolated)) {
+                continue;
+            }
+            let tap = self.tap(mirror);
+            self.rows.get_mut(offset).unwrap().push(tap);
+        }
+    }
+
+    // intervalだけ空いた単押しの間のpositionの位置にタップを足す
+    fn insert_taps(&mut self, interval: i32, position: i32, skippy: bool) {
+        let spans = self.hold_spans();
+        let columns = self.columns();
+        let mut added = Vec::new();
+        for pair in self.note_rows().windows(2) {
+            let ((start, Some(first)), (end, Some(second))) = (pair[0], pair[1]) else { continue };
+            let at = start + position;
+            if end - start != interval || start % interval != 0 || is_held(&spans, at, None) || self.rows.contains_key(&at) {
+                continue;
+            }
+            let column = if skippy { Some(first) } else { (1..columns).map(|d| (first + d) % columns).find(|c| *c != second) };
+            if let Some(column) = column {
+                added.push((at, self.tap(column)));
+            }
+        }
+        for (offset, tap) in added {
+            self.rows.insert(offset, vec![tap]);
+        }
+    }
+
+    fn echo(&mut self) {
+        let spans = self.hold_spans();
+        let rows = self.note_rows();
+        for (i, (offset, _)) in rows.iter().enumerate() {
+            let at = offset + BEAT / 2;
+            if rows.get(i + 1).is_some_and(|next| next.0 - offset < BEAT) || is_held(&spans, at, None) || self.rows.contains_key(&at) {
+                continue;
+            }
+            let taps: Vec<Arrow> = self.rows[offset].iter().filter(|a| a.arrow_type == ArrowType::Normal).map(|a| self.tap(a.column)).collect();
+            if !taps.is_empty() {
+                self.rows.insert(at, taps);
+            }
+        }
+    }
+
+    fn planted(&mut self) {
+        let rows = self.note_rows();
+        let mut held_until = i32::MIN;
+        for (i, (offset, _)) in rows.iter().enumerate() {
+            let arrows = self.rows.get_mut(offset).unwrap();
+            held_until = arrows.iter().filter(|a| a.is_hold()).map(|a| a.end).fold(held_until, i32::max);
+            let Some((next, _)) = rows.get(i + 1) else { break };
+            if held_until >= *offset {
+                continue;
+            }
+            let next_columns: Vec<usize> = self.rows[next].iter().map(|a| a.column).collect();
+            let arrows = self.rows.get_mut(offset).unwrap();
+            if let Some(arrow) = arrows.iter_mut().find(|a| a.arrow_type == ArrowType::Normal && !next_columns.contains(&a.column)) {
+                arrow.arrow_type = ArrowType::Freeze;
+                arrow.end = *next;
+                held_until = *next;
+            }
+        }
+    }
+
+    fn twister(&mut self) {
+        let spans = self.hold_spans();
+        let rows = self.note_rows();
+        let mut columns: Vec<Option<usize>> = rows.iter().map(|(_, column)| *column).collect();
+        for i in 2..rows.len() {
+            let (Some(a), Some(b), Some(c)) = (columns[i - 2], columns[i - 1], columns[i]) else { continue };
+            let same_pad = a / 4 == b / 4 && b / 4 == c / 4;
+            let side = |column: usize| column.is_multiple_of(4) || column % 4 == 3;
+            if !same_pad || a != c || !side(a) || side(b) || rows[i].0 - rows[i - 2].0 > BEAT {
+                continue;
+            }
+            let crossed = c / 4 * 4 + 3 - c % 4;
+            if is_held(&spans, rows[i].0, Some(crossed)) {
+                continue;
+            }
+            let directions = &self.directions;
+            for arrow in self.rows.get_mut(&rows[i].0).unwrap().iter_mut().filter(|a| a.column == c) {
+                arrow.column = crossed;
+                arrow.direction = directions[crossed];
+            }
+            columns[i] = Some(crossed);
+        }
+    }
+
+    fn apply(&mut self, transform: Transform, chart_type: ChartType) -> Result<(), String> {
+        let dance = matches!(chart_type, ChartType::DanceSingle | ChartType::DanceDouble);
+        match transform {
+            Transform::Left | Transform::Right | Transform::Twister if !dance => {
+                return Err(format!("{:?} is not supported for {:?}", transform, chart_type));
+            }
+            Transform::Mirror => {
+                let columns = self.columns();
+                self.remap(|c| columns - 1 - c);
+            }
+            Transform::Left => self.remap(|c| c / 4 * 4 + LEFT_TURN[c % 4]),
+            Transform::Right => self.remap(|c| c / 4 * 4 + RIGHT_TURN[c % 4]),
+            Transform::Shuffle(seed) => {
+                let mut rng = Rng(seed);
+                let identity: Vec<usize> = (0..self.columns()).collect();
+                let mut map = identity.clone();
+                // 元と同じ並びは選ばない
+                while map == identity && map.len() > 1 {
+                    rng.shuffle(&mut map);
+                }
+                self.remap(|c| map[c]);
+            }
+            Transform::SuperShuffle(seed) => self.super_shuffle(&mut Rng(seed)),
+            Transform::NoJumps => {
+                for arrows in self.rows.values_mut() {
+                    let mut kept = false;
+                    arrows.retain(|a| !a.is_note() || !std::mem::replace(&mut kept, true));
+                }
+            }
+            Transform::NoHands => self.no_hands(),
+            Transform::NoHolds => {
+                for arrow in self.rows.values_mut().flatten().filter(|a| a.is_hold()) {
+                    arrow.arrow_type = ArrowType::Normal;
+                    arrow.end = 0;
+                }
+            }
+            Transform::NoMines => self.rows.values_mut().for_each(|arrows| arrows.retain(|a| a.arrow_type != ArrowType::Mine)),
+            Transform::Little => self.rows.retain(|offset, _| offset % BEAT == 0),
+            Transform::Wide => self.add_mirror_jumps(true),
+            Transform::Stomp => self.add_mirror_jumps(false),
+            Transform::Big => self.insert_taps(BEAT, BEAT / 2, false),
+            Transform::Quick => self.insert_taps(BEAT / 2, BEAT / 4, false),
+            Transform::Skippy => self.insert_taps(BEAT, BEAT * 3 / 4, true),
+            Transform::Echo => self.echo(),
+            Transform::Planted => self.planted(),
+            Transform::Twister => self.twister(),
+        }
+        Ok(())
+    }
+
+    fn into_divisions(self, bpms: &[Bpm], stops: &[Stop], time_signatures: &[TimeSignature]) -> Vec<Division> {
+        let measures = measure_starts(time_signatures, self.rows.keys().last().copied().unwrap_or(0));
+        self.rows
+            .into_iter()
+            .filter(|(_, arrows)| !arrows.is_empty())
+            .map(|(offset, mut arrows)| {
+                // 同じ列に重なったものは先のものを残す
+                arrows.sort_by_key(|a| a.column);
+                arrows.dedup_by_key(|a| a.column);
+                for arrow in arrows.iter_mut() {
+                    arrow.direction = self.directions[arrow.column];
+                    arrow.end_time = offset_to_time(arrow.end, bpms, stops);
+                    arrow.foot = None;
+                }
+                Division { arrows, color: ofs_to_color(offset), offset, time: offset_to_time(offset, bpms, stops), measure: measure_of(&measures, offset) }
+            })
+            .collect()
+    }
+}
+
+// transformsを順に適用した譜面を作る。グルーブレーダーはeraの計算式で計算し直す
+pub fn transform_chart(chart: &Chart, transforms: &[Transform], era: RadarEra, with_detail: bool) -> Result<Chart, String> {
+    let chart_type = chart.info.chart_type;
+    let mut notes = Notes {
+        rows: chart.notes().iter().map(|d| (d.offset, d.arrows.clone())).collect(),
+        directions: chart_type.directions(),
+    };
+    for transform in transforms {
+        notes.apply(*transform, chart_type)?;
+    }
+    let divisions = notes.into_divisions(&chart.bpms, &chart.stops, &chart.time_signatures);
+    // ノーツが全部消えた譜面は出力しない
+    if divisions.is_empty() {
+        return Err(format!("no notes left after {}", transforms.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(",")));
+    }
+    let mut transformed = make_chart(chart_type, chart.info.difficulty, chart.info.level, divisions, &chart.bpms, &chart.stops, &chart.time_signatures);
+    transformed.info.transforms = chart.info.transforms.iter().cloned().chain(transforms.iter().map(|t| t.to_string())).collect();
+    transformed.music_length = chart.music_length;
+    transformed.update_groove_radar(era, with_detail);
+    transformed.update_scroll_speed(chart.info.scroll_speed.target_read_speed);
+    Ok(transformed)
+}
+
+// 音楽をrate倍の速さにした譜面。BPMはrate倍、停止と時間は1/rate倍になる
+// グルーブレーダーはeraの計算式で計算し直す
+pub fn rate_chart(chart: &Chart, rate: f32, era: RadarEra, with_detail: bool) -> Chart {
+    let bpms: Vec<Bpm> = chart.bpms.iter().map(|b| Bpm { bpm: b.bpm * rate, ..*b }).collect();
+    let stops: Vec<Stop> = chart.stops.iter().map(|s| Stop { time: s.time / rate, ..*s }).collect();
+    let mut notes = chart.notes().to_vec();
+    for division in notes.iter_mut() {
+        division.time /= rate;
+        for arrow in division.arrows.iter_mut() {
+            arrow.end_time /= rate;
+        }
+    }
+    let mut rated = make_chart(chart.info.chart_type, chart.info.difficulty, chart.info.level, notes, &bpms, &stops, &chart.time_signatures);
+    rated.info.transforms = chart.info.transforms.clone();
+    // 曲の終わりも速くなるが、最後のノーツの後に足す秒数はそのまま
+    rated.music_length = MusicLength { end: chart.music_length.end.map(|end| end / rate), tail: chart.music_length.tail };
+    rated.update_groove_radar(era, with_detail);
+    rated.update_scroll_speed(chart.info.scroll_speed.target_read_speed);
+    rated
+}
+
+#[test]
+fn test_transform_chart() {
+    use crate::chart::{Difficulty, str_to_notes};
+    let bpms = [Bpm { offset: 0, bpm: 150.0 }];
+    let time_signatures = [TimeSignature { offset: 0, numerator: 4, denominator: 4 }];
+    let chart = |bar: &str| {
+        let notes = str_to_notes(vec![bar], ChartType::DanceSingle, &bpms, &[], &time_signatures);
+        make_chart(ChartType::DanceSingle, Difficulty::Hard, 5, notes, &bpms, &[], &time_signatures)
+    };
+    let rows = |chart: &Chart| {
+        chart
+            .notes()
+            .iter()
+            .map(|d| {
+                let mut row = ['0'; 4];
+                for arrow in &d.arrows {
+                    row[arrow.column] = if arrow.is_hold() { '2' } else { '1' };
+                }
+                (d.offset / 12, row.iter().collect::<String>())
+            })
+            .collect::<Vec<_>>()
+    };
+    let transformed = |bar: &str, transforms: &str| {
+        let transforms: Vec<Transform> = transforms.split(',').map(|t| t.parse().unwrap()).collect();
+        rows(&transform_chart(&chart(bar), &transforms, RadarEra::X, false).unwrap())
+    };
+    // 左, 下, 上, 右+freeze(8分後に終わる)
+    let bar = "1000\n0100\n0010\n0002\n0003\n0000\n0000\n0000\n0000\n0000\n0000\n0000\n0000\n0000\n0000\n0000";
+    assert_eq!(transformed(bar, "mirror"), vec![(0, "0001".into()), (1, "0010".into()), (2, "0100".into()), (3, "2000".into())]);
+    assert_eq!(transformed(bar, "left"), vec![(0, "0100".into()), (1, "0001".into()), (2, "1000".into()), (3, "0020".into())]);
+    assert_eq!(transformed(bar, "left,right"), rows(&chart(bar)));
+    assert_eq!(transformed(bar, "noholds,little"), vec![(0, "1000".into())]);
+    let shuffled = transform_chart(&chart(bar), &[Transform::Shuffle(42)], RadarEra::X, false).unwrap();
+    assert_eq!(rows(&shuffled), transformed(bar, "shuffle:42"));
+    assert_ne!(rows(&shuffled), rows(&chart(bar)));
+    assert_eq!(shuffled.info.steps.holds, 1);
+
+    // 4分の左, 右, 左の間に8分を足してから、freezeにする
+    let quarters = "1000\n0001\n1000\n0000";
+    assert_eq!(
+        transformed(quarters, "big"),
+        vec![(0, "1000".into()), (2, "0100".into()), (4, "0001".into()), (6, "0100".into()), (8, "1000".into())]
+    );
+    let planted = transform_chart(&chart(quarters), &[Transform::Planted], RadarEra::X, false).unwrap();
+    assert_eq!(rows(&planted), vec![(0, "2000".into()), (4, "0001".into()), (8, "1000".into())]);
+    assert_eq!(planted.notes()[0].arrows[0].end, 48);
+    assert!(planted.info.freeze > 0);
+    // 8分の左, 下, 左は3つ目を右にして交差させる
+    let eighths = "1000\n0100\n1000\n0000\n0000\n0000\n0000\n0000";
+    assert_eq!(transformed(eighths, "twister"), vec![(0, "1000".into()), (2, "0100".into()), (4, "0001".into())]);
+    assert!(transform_chart(&chart(quarters), &[Transform::Stomp], RadarEra::X, false).unwrap().notes().iter().all(|d| d.arrows.len() == 2));
+    assert_eq!("shuffle".parse::<Transform>(), Ok(Transform::Shuffle(0)));
+    assert_eq!(shuffled.info.transforms, vec!["shuffle:42"]);
+    assert_eq!(Transform::NoJumps.to_string().parse::<Transform>(), Ok(Transform::NoJumps));
+
+    // 1.5倍速ではBPMが225になり、時間が2/3になる
+    let original = chart(bar);
+    let rated = rate_chart(&original, 1.5, RadarEra::X, false);
+    assert_eq!(rated.bpms[0].bpm, 225.0);
+    assert_eq!(rows(&rated), rows(&original));
+    assert!((rated.notes()[3].time - original.notes()[3].time / 1.5).abs() < 1e-5);
+    assert!((rated.notes()[3].arrows[0].end_time - original.notes()[3].arrows[0].end_time / 1.5).abs() < 1e-5);
+    assert!(rated.info.voltage > original.info.voltage);
+    assert_eq!(rate_chart(&shuffled, 1.5, RadarEra::X, false).info.transforms, shuffled.info.transforms);
+    assert!("mirror:1".parse::<Transform>().is_err());
+
+    // ノーツが全部消える場合はエラーにする
+    let offbeat = "0000\n1000\n0000\n0100\n0000\n0000\n0000\n0000";
+    assert!(transform_chart(&chart(offbeat), &[Transform::Little], RadarEra::X, false).is_err());
+    assert!(transform_chart(&chart("M000\n0000\n0M00\n0000"), &[Transform::NoMines], RadarEra::X, false).is_err());
+    // 地雷だけが残る譜面もパニックしない
+    let mines = transform_chart(&chart("M000\n1000\n0000\n0000\n0000\n0000\n0000\n0000"), &[Transform::Little], RadarEra::X, false).unwrap();
+    assert_eq!(mines.info.steps.taps, 0);
+}